@@ -0,0 +1,129 @@
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Socket, Type};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use tokio::net::UdpSocket;
+use tokio::time::Duration;
+
+const BEACON_MAGIC: &[u8; 4] = b"RCBN";
+/// How often we announce ourselves.
+pub const BEACON_INTERVAL: Duration = Duration::from_secs(5);
+/// Largest beacon we send or read; well under one Ethernet frame.
+const MAX_BEACON_SIZE: usize = 1200;
+/// Addresses listed in a beacon; the sender's own source address is used
+/// as well.
+pub const MAX_ADDRS: usize = 16;
+
+/// What a beacon announces: the same as our mDNS service record.
+#[derive(Serialize, Deserialize)]
+pub struct Beacon {
+    /// The mDNS service type, so that other software on the port is ignored.
+    pub service: String,
+    pub instance: String,
+    pub tag: String,
+    pub port: u16,
+    pub addrs: Vec<IpAddr>,
+}
+
+impl Beacon {
+    fn encode(&self) -> Option<Vec<u8>> {
+        let mut data = BEACON_MAGIC.to_vec();
+        data.extend(bincode::serialize(self).ok()?);
+        (data.len() <= MAX_BEACON_SIZE).then_some(data)
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let body = data.strip_prefix(BEACON_MAGIC)?;
+        let mut beacon: Self = bincode::deserialize(body).ok()?;
+        beacon.addrs.truncate(MAX_ADDRS);
+        Some(beacon)
+    }
+}
+
+/// UDP broadcast and multicast discovery, for networks where mDNS does not
+/// work, e.g. because another responder on the host holds its port.
+pub struct Beacons {
+    v4: UdpSocket,
+    v6: Option<UdpSocket>,
+}
+
+impl Beacons {
+    /// Binds `port` on IPv4 and, unless `ipv4_only`, IPv6. Several
+    /// instances on one host can share the port.
+    pub fn bind(port: u16, ipv4_only: bool) -> std::io::Result<Self> {
+        let v4 = bind(SocketAddr::from(([0, 0, 0, 0], port)))?;
+        let v6 = if ipv4_only {
+            None
+        } else {
+            bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port))).ok()
+        };
+        Ok(Self { v4, v6 })
+    }
+
+    /// Sends `beacon` to each of `targets`, skipping the families we have
+    /// no socket for. Failures on one interface do not stop the rest.
+    pub async fn send(&self, beacon: &Beacon, targets: &[SocketAddr]) {
+        let Some(data) = beacon.encode() else {
+            return;
+        };
+        for target in targets {
+            let socket = match target {
+                SocketAddr::V4(_) => Some(&self.v4),
+                SocketAddr::V6(_) => self.v6.as_ref(),
+            };
+            if let Some(socket) = socket {
+                let _ = socket.send_to(&data, target).await;
+            }
+        }
+    }
+
+    /// Waits for the next beacon, returning it with the address it came
+    /// from. Anything else arriving on the port is dropped, and a failed
+    /// receive is reported without giving up on either socket.
+    pub async fn recv(&self) -> (Beacon, IpAddr) {
+        let mut v4_buf = [0u8; MAX_BEACON_SIZE];
+        let mut v6_buf = [0u8; MAX_BEACON_SIZE];
+        loop {
+            let received = tokio::select! {
+                received = self.v4.recv_from(&mut v4_buf) => {
+                    received.map(|(len, from)| (&v4_buf[..len], from))
+                }
+                received = recv_v6(&self.v6, &mut v6_buf) => {
+                    received.map(|(len, from)| (&v6_buf[..len], from))
+                }
+            };
+            let (data, from) = match received {
+                Ok(received) => received,
+                Err(e) => {
+                    eprintln!("Beacon receive error: {}", e);
+                    continue;
+                }
+            };
+            if let Some(beacon) = Beacon::decode(data) {
+                return (beacon, from.ip());
+            }
+        }
+    }
+}
+
+async fn recv_v6(
+    socket: &Option<UdpSocket>,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+fn bind(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    } else {
+        socket.set_broadcast(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
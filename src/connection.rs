@@ -0,0 +1,619 @@
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::{SocketAddr, SocketAddrV6};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::{Mutex, mpsc};
+use tokio::task::JoinSet;
+use tokio::time::{Duration, sleep, timeout};
+
+use crate::Message;
+use crate::frame::IncompatibleVersion;
+use crate::identity::{self, fingerprint_digest};
+use crate::session::{self, FrameError, LocalPeer, Session};
+
+const OUTGOING_QUEUE_SIZE: usize = 64;
+const EVENT_QUEUE_SIZE: usize = 256;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a peer that accepted the connection gets to finish the key
+/// exchange, so a stalled or incompatible one cannot hold a dial lock or an
+/// accept task forever.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_ATTEMPTS: u32 = 4;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// Head start each of a peer's addresses gets before the next is tried.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+/// Longest `sender_for` keeps trying a peer: every attempt running into
+/// both timeouts, plus the backoff between them.
+const DIAL_BUDGET: Duration = CONNECT_TIMEOUT
+    .saturating_add(HANDSHAKE_TIMEOUT)
+    .saturating_mul(CONNECT_ATTEMPTS)
+    .saturating_add(INITIAL_BACKOFF.saturating_mul((1 << (CONNECT_ATTEMPTS - 1)) - 1));
+/// Upper bound on dialling plus queueing for one peer, so a firewalled or
+/// stalled peer cannot hold up delivery to everyone else. Leaves a full
+/// redial its backoff.
+const SEND_TIMEOUT: Duration = DIAL_BUDGET.saturating_add(Duration::from_secs(5));
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// How much bandwidth sessions spend on hiding traffic patterns.
+#[derive(Clone, Copy, Default)]
+pub struct TrafficPolicy {
+    /// Pad every frame to a size bucket, hiding exact message lengths.
+    pub pad: bool,
+    /// Mean time between dummy frames on each session, if any. Dummy frames
+    /// are padded like real ones and dropped by the receiver, so an
+    /// observer cannot tell when anyone is actually talking.
+    pub cover_interval: Option<Duration>,
+}
+
+/// Something that happened on one of the peer sessions.
+pub enum ConnectionEvent {
+    /// A session finished its handshake. `addr` is the peer's listening address.
+    Connected {
+        addr: SocketAddr,
+        peer_name: String,
+        peer_key: [u8; 32],
+    },
+    Message {
+        addr: SocketAddr,
+        peer_key: [u8; 32],
+        message: Message,
+    },
+    /// A frame from `addr` reused a sequence number and was dropped.
+    Replayed { addr: SocketAddr, sequence: u64 },
+    /// The session in use for `addr` closed.
+    Disconnected { addr: SocketAddr },
+}
+
+/// A peer dialled from an invite answered with a different identity key.
+#[derive(Debug)]
+pub struct UnexpectedIdentity {
+    pub addr: SocketAddr,
+    pub fingerprint: String,
+}
+
+impl std::fmt::Display for UnexpectedIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} answered as {}, not the peer in the invite",
+            self.addr, self.fingerprint
+        )
+    }
+}
+
+impl std::error::Error for UnexpectedIdentity {}
+
+/// A peer took the connection but never finished the handshake, which is
+/// also how builds that predate protocol versioning behave.
+#[derive(Debug)]
+pub struct HandshakeTimeout {
+    pub addr: SocketAddr,
+}
+
+impl std::fmt::Display for HandshakeTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} did not finish the handshake within {}s (it may run an older, incompatible build)",
+            self.addr,
+            HANDSHAKE_TIMEOUT.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for HandshakeTimeout {}
+
+struct Connection {
+    id: u64,
+    initiated_by_us: bool,
+    peer_key: [u8; 32],
+    outgoing: mpsc::Sender<Arc<[u8]>>,
+}
+
+/// Keeps one long-lived encrypted session per peer, shared by inbound and
+/// outbound traffic, and redials with backoff when it drops.
+pub struct ConnectionManager {
+    local: LocalPeer,
+    policy: TrafficPolicy,
+    connections: Mutex<HashMap<SocketAddr, Connection>>,
+    /// Serialises dialling per peer so concurrent sends share one handshake.
+    dial_locks: Mutex<HashMap<SocketAddr, Arc<Mutex<()>>>>,
+    events: mpsc::Sender<ConnectionEvent>,
+    next_id: AtomicU64,
+}
+
+impl ConnectionManager {
+    pub fn new(
+        local: LocalPeer,
+        policy: TrafficPolicy,
+    ) -> (Arc<Self>, mpsc::Receiver<ConnectionEvent>) {
+        let (events, receiver) = mpsc::channel(EVENT_QUEUE_SIZE);
+        let manager = Arc::new(Self {
+            local,
+            policy,
+            connections: Mutex::new(HashMap::new()),
+            dial_locks: Mutex::new(HashMap::new()),
+            events,
+            next_id: AtomicU64::new(0),
+        });
+        (manager, receiver)
+    }
+
+    /// Sends each address its serialized messages in order, all addresses
+    /// concurrently. Each session still encrypts under its own keys, but a
+    /// payload shared by several peers is passed as one `Arc` rather than
+    /// copied per peer.
+    pub async fn fan_out(
+        self: &Arc<Self>,
+        payloads: Vec<(SocketAddr, Vec<Arc<[u8]>>)>,
+    ) -> Vec<(SocketAddr, Result<(), String>)> {
+        let mut sends = JoinSet::new();
+        let mut tasks = HashMap::new();
+        for (addr, messages) in payloads {
+            let manager = self.clone();
+            let task = sends.spawn(async move {
+                let send_all = async {
+                    for data in messages {
+                        manager.send(addr, data).await?;
+                    }
+                    Ok::<_, BoxError>(())
+                };
+                let result = match timeout(SEND_TIMEOUT, send_all).await {
+                    Ok(Ok(())) => Ok(()),
+                    Ok(Err(e)) => Err(e.to_string()),
+                    Err(_) => Err("timed out".to_string()),
+                };
+                (addr, result)
+            });
+            tasks.insert(task.id(), addr);
+        }
+
+        let mut results = Vec::new();
+        while let Some(joined) = sends.join_next().await {
+            match joined {
+                Ok(result) => results.push(result),
+                Err(e) => results.push((tasks[&e.id()], Err(e.to_string()))),
+            }
+        }
+        results
+    }
+
+    /// Queues an already serialized message for the peer listening on `addr`,
+    /// connecting first if there is no live session.
+    pub async fn send(self: &Arc<Self>, addr: SocketAddr, data: Arc<[u8]>) -> Result<(), BoxError> {
+        let outgoing = self.sender_for(addr).await?;
+        outgoing
+            .send(data)
+            .await
+            .map_err(|_| format!("Connection to {} closed", addr).into())
+    }
+
+    /// Opens a session over whichever of a peer's addresses, given best
+    /// first, answers first. Each address gets [`ATTEMPT_DELAY`] before the
+    /// next is tried alongside it, so an unreachable one costs a moment
+    /// rather than a timeout. An address that accepts the connection but
+    /// fails the handshake, or turns out to be someone else, is passed over
+    /// for the rest. With `expected`, only a peer whose identity key has
+    /// that fingerprint will do. Returns the address the session runs over
+    /// and the peer's identity key.
+    pub async fn connect_any(
+        self: &Arc<Self>,
+        addrs: &[SocketAddr],
+        expected: Option<[u8; 16]>,
+    ) -> Result<(SocketAddr, [u8; 32]), BoxError> {
+        for addr in addrs {
+            if let Some(key) = self.live_key(*addr).await
+                && expected.is_none_or(|fingerprint| fingerprint == fingerprint_digest(&key))
+            {
+                return Ok((*addr, key));
+            }
+        }
+
+        let mut attempts = JoinSet::new();
+        for (i, addr) in addrs.iter().copied().enumerate() {
+            attempts.spawn(async move {
+                sleep(ATTEMPT_DELAY * i as u32).await;
+                (
+                    addr,
+                    timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await,
+                )
+            });
+        }
+
+        let mut last_error: BoxError = "No addresses to connect to".into();
+        while let Some(joined) = attempts.join_next().await {
+            let error = match joined {
+                Ok((addr, Ok(Ok(stream)))) => match self.establish(addr, stream, expected).await {
+                    Ok(key) => {
+                        attempts.abort_all();
+                        return Ok((addr, key));
+                    }
+                    Err(e) => e,
+                },
+                Ok((addr, Ok(Err(e)))) => format!("{}: {}", addr, e).into(),
+                Ok((addr, Err(_))) => format!("Timed out connecting to {}", addr).into(),
+                Err(_) => continue,
+            };
+            // Someone answering in the peer's place is worth reporting over
+            // an address that merely did not answer.
+            if !last_error.is::<UnexpectedIdentity>() {
+                last_error = error;
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Takes a stream `connect_any` dialled to `addr`, unless a send got a
+    /// session there first, in which case that one has to be the right peer.
+    /// Returns the peer's identity key.
+    async fn establish(
+        self: &Arc<Self>,
+        addr: SocketAddr,
+        stream: TcpStream,
+        expected: Option<[u8; 16]>,
+    ) -> Result<[u8; 32], BoxError> {
+        let dial_lock = self.dial_lock(addr).await;
+        let _dialing = dial_lock.lock().await;
+        match self.live_key(addr).await {
+            Some(key) => check_identity(addr, &key, expected).map(|()| key),
+            None => self.open(addr, stream, expected).await.map(|(_, key)| key),
+        }
+    }
+
+    /// Runs the handshake on an accepted socket and adopts the session.
+    pub async fn accept(self: &Arc<Self>, mut socket: TcpStream) -> Result<(), BoxError> {
+        let remote = socket.peer_addr()?;
+        let session = timeout(HANDSHAKE_TIMEOUT, Session::accept(&mut socket, &self.local))
+            .await
+            .map_err(|_| HandshakeTimeout { addr: remote })??;
+        if self.is_us(&session) {
+            return Ok(());
+        }
+        let addr = listen_addr(remote, session.peer_listen_port);
+        self.adopt(addr, socket, session, false).await;
+        Ok(())
+    }
+
+    /// Whether a session with the peer listening on `addr` is up.
+    pub async fn is_connected(&self, addr: SocketAddr) -> bool {
+        self.live_key(addr).await.is_some()
+    }
+
+    /// Whether a session is up, on any address, with the peer whose identity
+    /// key has `fingerprint`.
+    pub async fn is_connected_to(&self, fingerprint: [u8; 16]) -> bool {
+        self.connections.lock().await.values().any(|conn| {
+            !conn.outgoing.is_closed() && fingerprint_digest(&conn.peer_key) == fingerprint
+        })
+    }
+
+    async fn live_key(&self, addr: SocketAddr) -> Option<[u8; 32]> {
+        self.connections
+            .lock()
+            .await
+            .get(&addr)
+            .filter(|conn| !conn.outgoing.is_closed())
+            .map(|conn| conn.peer_key)
+    }
+
+    async fn live_sender(&self, addr: SocketAddr) -> Option<mpsc::Sender<Arc<[u8]>>> {
+        self.connections
+            .lock()
+            .await
+            .get(&addr)
+            .filter(|conn| !conn.outgoing.is_closed())
+            .map(|conn| conn.outgoing.clone())
+    }
+
+    async fn dial_lock(&self, addr: SocketAddr) -> Arc<Mutex<()>> {
+        self.dial_locks
+            .lock()
+            .await
+            .entry(addr)
+            .or_default()
+            .clone()
+    }
+
+    async fn sender_for(
+        self: &Arc<Self>,
+        addr: SocketAddr,
+    ) -> Result<mpsc::Sender<Arc<[u8]>>, BoxError> {
+        if let Some(outgoing) = self.live_sender(addr).await {
+            return Ok(outgoing);
+        }
+
+        let dial_lock = self.dial_lock(addr).await;
+        let _dialing = dial_lock.lock().await;
+
+        // Another task may have connected while we waited for the lock.
+        if let Some(outgoing) = self.live_sender(addr).await {
+            return Ok(outgoing);
+        }
+
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            match self.dial(addr).await {
+                Ok(outgoing) => return Ok(outgoing),
+                // Redialling will not change the version the peer runs.
+                Err(e)
+                    if attempt >= CONNECT_ATTEMPTS
+                        || e.is::<IncompatibleVersion>()
+                        || e.is::<HandshakeTimeout>() =>
+                {
+                    return Err(e);
+                }
+                Err(_) => {
+                    sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    async fn dial(self: &Arc<Self>, addr: SocketAddr) -> Result<mpsc::Sender<Arc<[u8]>>, BoxError> {
+        let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+            .await
+            .map_err(|_| format!("Timed out connecting to {}", addr))??;
+        self.open(addr, stream, None)
+            .await
+            .map(|(outgoing, _)| outgoing)
+    }
+
+    /// Runs the handshake on a stream we dialled and adopts the session.
+    /// Returns the sender for the peer and its identity key.
+    async fn open(
+        self: &Arc<Self>,
+        addr: SocketAddr,
+        mut stream: TcpStream,
+        expected: Option<[u8; 16]>,
+    ) -> Result<(mpsc::Sender<Arc<[u8]>>, [u8; 32]), BoxError> {
+        let session = timeout(
+            HANDSHAKE_TIMEOUT,
+            Session::initiate(&mut stream, &self.local),
+        )
+        .await
+        .map_err(|_| HandshakeTimeout { addr })??;
+        if self.is_us(&session) {
+            return Err(format!("{} is our own address", addr).into());
+        }
+        check_identity(addr, &session.peer_key, expected)?;
+        let peer_key = session.peer_key;
+        Ok((self.adopt(addr, stream, session, true).await, peer_key))
+    }
+
+    /// Whether a handshake reached this very instance, e.g. through an
+    /// mDNS record from a build that does not tag its instance names.
+    fn is_us(&self, session: &Session) -> bool {
+        session.peer_key == self.local.identity.public_key()
+    }
+
+    /// Registers a freshly handshaken session and starts its reader and
+    /// writer tasks. Returns the sender for the connection now in use.
+    ///
+    /// A peer keeps one session however many of its addresses are dialled:
+    /// one that loses to another session with the same identity key stops
+    /// being written to, and whatever still arrives on it is reported under
+    /// the address of the session in use.
+    async fn adopt(
+        self: &Arc<Self>,
+        addr: SocketAddr,
+        stream: TcpStream,
+        session: Session,
+        initiated_by_us: bool,
+    ) -> mpsc::Sender<Arc<[u8]>> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let session = Arc::new(session);
+        let (reader, writer) = stream.into_split();
+        let (outgoing, queue) = mpsc::channel(OUTGOING_QUEUE_SIZE);
+
+        let mut connections = self.connections.lock().await;
+        let existing = connections
+            .iter()
+            .find(|(_, conn)| conn.peer_key == session.peer_key && !conn.outgoing.is_closed())
+            .map(|(existing_addr, conn)| (*existing_addr, conn));
+        let kept = match existing {
+            Some((existing_addr, conn)) if !self.prefer_new(conn, initiated_by_us) => {
+                Some((existing_addr, conn.outgoing.clone()))
+            }
+            Some((existing_addr, _)) => {
+                // Dropping the old sender closes our end of it.
+                connections.remove(&existing_addr);
+                None
+            }
+            None => None,
+        };
+        if kept.is_none() {
+            connections.insert(
+                addr,
+                Connection {
+                    id,
+                    initiated_by_us,
+                    peer_key: session.peer_key,
+                    outgoing: outgoing.clone(),
+                },
+            );
+        }
+        drop(connections);
+
+        if kept.is_none() {
+            let _ = self
+                .events
+                .send(ConnectionEvent::Connected {
+                    addr,
+                    peer_name: session.peer_name.clone(),
+                    peer_key: session.peer_key,
+                })
+                .await;
+        }
+
+        // The reader of a losing session stays up until the peer hangs up,
+        // so nothing in flight on it is lost.
+        let manager = self.clone();
+        tokio::spawn(manager.read_loop(id, addr, reader, session.clone()));
+        tokio::spawn(Self::write_loop(
+            writer,
+            session.clone(),
+            queue,
+            self.policy,
+        ));
+
+        match kept {
+            Some((_, existing)) => existing,
+            None => outgoing,
+        }
+    }
+
+    /// When both sides dial each other at once, both keep the connection
+    /// opened by whichever side has the lower identity key.
+    fn prefer_new(&self, existing: &Connection, new_initiated_by_us: bool) -> bool {
+        if existing.initiated_by_us == new_initiated_by_us {
+            return true;
+        }
+        let we_are_lower = self.local.identity.public_key() < existing.peer_key;
+        new_initiated_by_us == we_are_lower
+    }
+
+    /// The address of the session in use for `peer_key`, which frames read
+    /// from `addr` are reported under.
+    async fn route(&self, addr: SocketAddr, peer_key: &[u8; 32]) -> SocketAddr {
+        self.connections
+            .lock()
+            .await
+            .iter()
+            .find(|(_, conn)| conn.peer_key == *peer_key && !conn.outgoing.is_closed())
+            .map_or(addr, |(in_use, _)| *in_use)
+    }
+
+    async fn read_loop(
+        self: Arc<Self>,
+        id: u64,
+        addr: SocketAddr,
+        mut reader: OwnedReadHalf,
+        session: Arc<Session>,
+    ) {
+        loop {
+            let encrypted = match session::read_frame(&mut reader).await {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("Connection error from {}: {}", addr, e);
+                    break;
+                }
+            };
+
+            let decrypted = match session.decrypt(&encrypted) {
+                Ok(decrypted) => decrypted,
+                Err(FrameError::Replayed(sequence)) => {
+                    let addr = self.route(addr, &session.peer_key).await;
+                    let event = ConnectionEvent::Replayed { addr, sequence };
+                    if self.events.send(event).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                Err(FrameError::Invalid) => continue,
+            };
+            if decrypted.is_empty() {
+                // Cover traffic.
+                continue;
+            }
+
+            if let Ok(mut message) = bincode::deserialize::<Message>(&decrypted) {
+                // Whatever a message claims, everything on the session comes
+                // from the name its handshake authenticated.
+                message.sender.clone_from(&session.peer_name);
+                let event = ConnectionEvent::Message {
+                    addr: self.route(addr, &session.peer_key).await,
+                    peer_key: session.peer_key,
+                    message,
+                };
+                if self.events.send(event).await.is_err() {
+                    break;
+                }
+            }
+        }
+
+        let mut connections = self.connections.lock().await;
+        if connections.get(&addr).is_some_and(|conn| conn.id == id) {
+            connections.remove(&addr);
+            drop(connections);
+            let _ = self
+                .events
+                .send(ConnectionEvent::Disconnected { addr })
+                .await;
+        }
+    }
+
+    async fn write_loop(
+        mut writer: OwnedWriteHalf,
+        session: Arc<Session>,
+        mut queue: mpsc::Receiver<Arc<[u8]>>,
+        policy: TrafficPolicy,
+    ) {
+        loop {
+            let data = tokio::select! {
+                data = queue.recv() => match data {
+                    Some(data) => data,
+                    None => break,
+                },
+                _ = cover_delay(policy.cover_interval) => Arc::from(&[][..]),
+            };
+            let encrypted = match session.encrypt(&data, policy.pad) {
+                Ok(encrypted) => encrypted,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    continue;
+                }
+            };
+            if session::write_frame(&mut writer, &encrypted).await.is_err() {
+                break;
+            }
+        }
+        let _ = writer.shutdown().await;
+    }
+}
+
+/// Fails unless `key`, found at `addr`, has the `expected` fingerprint.
+fn check_identity(
+    addr: SocketAddr,
+    key: &[u8; 32],
+    expected: Option<[u8; 16]>,
+) -> Result<(), BoxError> {
+    if expected.is_some_and(|fingerprint| fingerprint != fingerprint_digest(key)) {
+        return Err(UnexpectedIdentity {
+            addr,
+            fingerprint: identity::fingerprint(key),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// The address a peer that connected from `remote` listens on. IPv4 peers
+/// reaching our dual-stack listener show up as IPv4-mapped IPv6 addresses,
+/// which are turned back into plain IPv4 ones to match what they advertise.
+fn listen_addr(remote: SocketAddr, port: u16) -> SocketAddr {
+    match remote {
+        SocketAddr::V6(v6) if v6.ip().to_ipv4_mapped().is_none() => {
+            SocketAddr::V6(SocketAddrV6::new(*v6.ip(), port, 0, v6.scope_id()))
+        }
+        _ => SocketAddr::new(remote.ip().to_canonical(), port),
+    }
+}
+
+/// Waits a random, exponentially distributed time with the given mean, so
+/// dummy frames arrive like a Poisson process. Never fires without a mean.
+async fn cover_delay(mean: Option<Duration>) {
+    let Some(mean) = mean else {
+        return std::future::pending().await;
+    };
+    let uniform: f64 = rand::thread_rng().r#gen();
+    sleep(mean.mul_f64(-(1.0 - uniform).ln())).await;
+}
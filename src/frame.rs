@@ -0,0 +1,152 @@
+use std::fmt;
+
+use crate::MAX_MESSAGE_SIZE;
+
+/// Marks the start of every frame, so a peer speaking something else is
+/// told apart from one that merely fails to decrypt.
+const MAGIC: [u8; 4] = *b"RCHT";
+/// Oldest and newest protocol versions this build speaks.
+pub const MIN_VERSION: u8 = 1;
+pub const MAX_VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 8;
+
+/// The body was padded with [`pad`] before encryption.
+pub const FLAG_PADDED: u16 = 1;
+const KNOWN_FLAGS: u16 = FLAG_PADDED;
+/// Smallest padded size, so short chat lines all look alike.
+const MIN_BUCKET: usize = 256;
+/// Padded sizes double up to this, then grow in steps of it.
+const LARGE_BUCKET: usize = 64 * 1024;
+/// Leaves room for the header, sequence number, ratchet header and tag.
+const MAX_PADDED: usize = MAX_MESSAGE_SIZE - 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameType {
+    /// Plaintext ephemeral key and supported versions. Its body keeps the
+    /// version 1 layout in every version so that any two peers can agree.
+    KeyExchange = 1,
+    /// Signed identity, encrypted under the handshake keys.
+    Identity = 2,
+    /// Application message, encrypted under the session ratchet.
+    Data = 3,
+}
+
+/// Header in front of every frame. Encrypted frames authenticate it as
+/// associated data, so it cannot be altered in transit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub version: u8,
+    pub frame_type: FrameType,
+    /// Frames carrying flags we do not know are rejected.
+    pub flags: u16,
+}
+
+impl FrameHeader {
+    pub fn new(version: u8, frame_type: FrameType) -> Self {
+        Self {
+            version,
+            frame_type,
+            flags: 0,
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        bytes[..4].copy_from_slice(&MAGIC);
+        bytes[4] = self.version;
+        bytes[5] = self.frame_type as u8;
+        bytes[6..].copy_from_slice(&self.flags.to_be_bytes());
+        bytes
+    }
+
+    /// Splits a frame into its header and body. `None` means the frame
+    /// does not start with a header at all, as sent by builds from before
+    /// protocol versioning.
+    pub fn parse(frame: &[u8]) -> Option<(Self, &[u8])> {
+        if frame.len() < HEADER_SIZE || frame[..4] != MAGIC {
+            return None;
+        }
+        let frame_type = match frame[5] {
+            1 => FrameType::KeyExchange,
+            2 => FrameType::Identity,
+            3 => FrameType::Data,
+            _ => return None,
+        };
+        let header = Self {
+            version: frame[4],
+            frame_type,
+            flags: u16::from_be_bytes([frame[6], frame[7]]),
+        };
+        Some((header, &frame[HEADER_SIZE..]))
+    }
+
+    pub fn with_flags(self, flags: u16) -> Self {
+        Self { flags, ..self }
+    }
+
+    /// Whether this is a frame of `frame_type` and `version` that we can read.
+    pub fn is(&self, version: u8, frame_type: FrameType) -> bool {
+        self.version == version && self.frame_type == frame_type && self.flags & !KNOWN_FLAGS == 0
+    }
+
+    /// Header and body together, ready for `write_frame`.
+    pub fn wrap(self, body: &[u8]) -> Vec<u8> {
+        let mut frame = self.to_bytes().to_vec();
+        frame.extend_from_slice(body);
+        frame
+    }
+}
+
+/// Picks the newest version both sides speak, given each side's range.
+pub fn negotiate(ours: (u8, u8), theirs: (u8, u8)) -> Option<u8> {
+    let version = ours.1.min(theirs.1);
+    (version >= ours.0 && version >= theirs.0).then_some(version)
+}
+
+/// Pads `data` up to its size bucket, so an observer only learns roughly
+/// how big a message is. The padding is a 0x80 byte followed by zeros.
+pub fn pad(data: &[u8]) -> Vec<u8> {
+    let len = data.len() + 1;
+    let bucket = if len <= LARGE_BUCKET {
+        len.next_power_of_two().max(MIN_BUCKET)
+    } else {
+        len.div_ceil(LARGE_BUCKET) * LARGE_BUCKET
+    };
+
+    let mut padded = data.to_vec();
+    padded.push(0x80);
+    padded.resize(bucket.min(MAX_PADDED).max(len), 0);
+    padded
+}
+
+/// Strips the padding added by [`pad`].
+pub fn unpad(data: &[u8]) -> Option<&[u8]> {
+    let end = data.iter().rposition(|&byte| byte != 0)?;
+    (data[end] == 0x80).then_some(&data[..end])
+}
+
+/// The remote side of a handshake speaks no protocol version we do. Its
+/// message leaves out who the peer is, which the caller knows better.
+#[derive(Debug)]
+pub struct IncompatibleVersion {
+    /// Versions the peer supports, or `None` for a build that predates
+    /// versioning.
+    pub versions: Option<(u8, u8)>,
+}
+
+impl fmt::Display for IncompatibleVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.versions {
+            Some((min, max)) if min == max => write!(f, "incompatible protocol version {}", min)?,
+            Some((min, max)) => write!(f, "incompatible protocol versions {}-{}", min, max)?,
+            None => write!(f, "unversioned protocol from an older build")?,
+        }
+        write!(f, " (this build speaks {}", MIN_VERSION)?;
+        if MAX_VERSION != MIN_VERSION {
+            write!(f, "-{}", MAX_VERSION)?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl std::error::Error for IncompatibleVersion {}
@@ -0,0 +1,43 @@
+use rand::seq::SliceRandom;
+use std::net::SocketAddr;
+use tokio::time::Duration;
+
+use crate::identity::{self, Identity};
+
+const GOSSIP_SIGNATURE_CONTEXT: &[u8] = b"rust-chat 2025 gossip signature";
+/// Channels with more members than this are reached by gossip rather than
+/// by sending every member a copy.
+pub const THRESHOLD: usize = 8;
+/// Members each node passes a new message on to.
+pub const FANOUT: usize = 3;
+/// Times a message may be passed on after leaving its sender. With a
+/// fanout of 3 this covers far more peers than a LAN ever holds.
+pub const MAX_HOPS: u8 = 6;
+/// How long members have to confirm a gossiped message before the sender
+/// hands it to the rest directly. Each misses a fanout-3 rumour about one
+/// time in twenty.
+pub const REPAIR_AFTER: Duration = Duration::from_secs(5);
+
+/// Signs a serialized group message so that members receiving it from
+/// someone else can tell it really comes from `identity`. Every member
+/// holds the sender key, so the encryption alone does not prove that.
+pub fn sign(identity: &Identity, data: &[u8]) -> Vec<u8> {
+    identity.sign(&signed_data(data)).to_vec()
+}
+
+pub fn verify(origin: &[u8; 32], data: &[u8], signature: &[u8]) -> bool {
+    identity::verify(origin, &signed_data(data), signature)
+}
+
+/// Up to [`FANOUT`] members picked at random to pass a message to.
+pub fn pick(mut members: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    members.shuffle(&mut rand::thread_rng());
+    members.truncate(FANOUT);
+    members
+}
+
+fn signed_data(data: &[u8]) -> Vec<u8> {
+    let mut signed = GOSSIP_SIGNATURE_CONTEXT.to_vec();
+    signed.extend_from_slice(data);
+    signed
+}
@@ -0,0 +1,216 @@
+use chacha20poly1305::{
+    ChaCha20Poly1305, Nonce,
+    aead::{Aead, KeyInit, Payload},
+};
+use rand::Rng;
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+
+use crate::ratchet::chain_step;
+
+/// How far ahead of the last message a sender may jump in its chain.
+const MAX_SKIP: u32 = 1000;
+
+/// A room (or the lobby, `None`) that shares sender keys.
+pub type Channel = Option<String>;
+
+/// Our sender key as handed to a member: enough to decrypt messages from
+/// `iteration` on, but nothing sent before it.
+pub struct SenderKey {
+    pub key_id: u32,
+    pub chain_key: [u8; 32],
+    pub iteration: u32,
+}
+
+/// Result of encrypting a message once for the whole channel.
+pub struct GroupCiphertext {
+    pub key_id: u32,
+    pub iteration: u32,
+    pub ciphertext: Vec<u8>,
+}
+
+struct OwnKey {
+    key_id: u32,
+    chain: [u8; 32],
+    iteration: u32,
+    /// Peers the key was handed to; any change to the channel's membership
+    /// retires the key.
+    members: BTreeSet<SocketAddr>,
+}
+
+struct PeerKey {
+    key_id: u32,
+    chain: [u8; 32],
+    iteration: u32,
+    skipped: HashMap<u32, [u8; 32]>,
+}
+
+/// Sender keys for group traffic.
+///
+/// Each member encrypts a broadcast once under its own hash-chained sender
+/// key, which it hands to the other members over their pairwise sessions.
+/// The chain moves forward with every message, and a new key is generated
+/// whenever someone joins or leaves, so departed peers cannot read new
+/// traffic and new ones cannot read old traffic.
+#[derive(Default)]
+pub struct GroupKeys {
+    own: HashMap<Channel, OwnKey>,
+    peers: HashMap<(SocketAddr, Channel), PeerKey>,
+}
+
+impl GroupKeys {
+    /// Gets our key for `channel` ready to send to `members`, replacing it
+    /// if the membership changed. Returns the key as it stands before the
+    /// next message, if the members need it handed out.
+    pub fn prepare(
+        &mut self,
+        channel: &Channel,
+        members: &BTreeSet<SocketAddr>,
+    ) -> Option<SenderKey> {
+        if self
+            .own
+            .get(channel)
+            .is_some_and(|own| own.members == *members)
+        {
+            return None;
+        }
+
+        let mut rng = rand::thread_rng();
+        let own = OwnKey {
+            key_id: rng.r#gen(),
+            chain: rng.r#gen(),
+            iteration: 0,
+            members: members.clone(),
+        };
+        let key = SenderKey {
+            key_id: own.key_id,
+            chain_key: own.chain,
+            iteration: own.iteration,
+        };
+        self.own.insert(channel.clone(), own);
+        Some(key)
+    }
+
+    /// Our current key for `channel`, if `addr` is one of the members it was
+    /// made for; used when a member lost it.
+    pub fn current(&self, channel: &Channel, addr: SocketAddr) -> Option<SenderKey> {
+        let own = self.own.get(channel)?;
+        own.members.contains(&addr).then_some(SenderKey {
+            key_id: own.key_id,
+            chain_key: own.chain,
+            iteration: own.iteration,
+        })
+    }
+
+    pub fn encrypt(&mut self, channel: &Channel, data: &[u8]) -> Result<GroupCiphertext, String> {
+        let own = self.own.get_mut(channel).ok_or("No sender key")?;
+        let message_key = chain_step(&mut own.chain);
+        let iteration = own.iteration;
+        own.iteration += 1;
+
+        let ciphertext = ChaCha20Poly1305::new((&message_key).into())
+            .encrypt(
+                Nonce::from_slice(&[0u8; 12]),
+                Payload {
+                    msg: data,
+                    aad: &associated_data(channel, own.key_id, iteration),
+                },
+            )
+            .map_err(|e| format!("Encryption error: {}", e))?;
+
+        Ok(GroupCiphertext {
+            key_id: own.key_id,
+            iteration,
+            ciphertext,
+        })
+    }
+
+    /// Stores a sender key handed to us by the member at `addr`.
+    pub fn install(&mut self, addr: SocketAddr, channel: &Channel, key: SenderKey) {
+        let id = (addr, channel.clone());
+        if self
+            .peers
+            .get(&id)
+            .is_some_and(|existing| existing.key_id == key.key_id)
+        {
+            return;
+        }
+        self.peers.insert(
+            id,
+            PeerKey {
+                key_id: key.key_id,
+                chain: key.chain_key,
+                iteration: key.iteration,
+                skipped: HashMap::new(),
+            },
+        );
+    }
+
+    /// Decrypts a group message from `addr`. `Ok(None)` means we do not hold
+    /// the key it was sent under and should ask the sender for it.
+    pub fn decrypt(
+        &mut self,
+        addr: SocketAddr,
+        channel: &Channel,
+        message: &GroupCiphertext,
+    ) -> Result<Option<Vec<u8>>, String> {
+        let Some(peer) = self
+            .peers
+            .get_mut(&(addr, channel.clone()))
+            .filter(|peer| peer.key_id == message.key_id)
+        else {
+            return Ok(None);
+        };
+
+        let message_key = if let Some(key) = peer.skipped.remove(&message.iteration) {
+            key
+        } else if message.iteration < peer.iteration {
+            return Err("Group message replayed or too old".to_string());
+        } else if message.iteration - peer.iteration > MAX_SKIP {
+            return Err("Too many skipped group messages".to_string());
+        } else {
+            while peer.iteration < message.iteration {
+                let key = chain_step(&mut peer.chain);
+                peer.skipped.insert(peer.iteration, key);
+                peer.iteration += 1;
+            }
+            peer.iteration += 1;
+            chain_step(&mut peer.chain)
+        };
+
+        // Each message key encrypts exactly one message, so a fixed nonce is safe.
+        let plaintext = ChaCha20Poly1305::new((&message_key).into()).decrypt(
+            Nonce::from_slice(&[0u8; 12]),
+            Payload {
+                msg: &message.ciphertext,
+                aad: &associated_data(channel, message.key_id, message.iteration),
+            },
+        );
+        match plaintext {
+            Ok(plaintext) => Ok(Some(plaintext)),
+            Err(e) => {
+                // Keep the key so a forged copy cannot block the real message.
+                peer.skipped.insert(message.iteration, message_key);
+                Err(format!("Decryption error: {}", e))
+            }
+        }
+    }
+
+    /// Drops the keys `addr` gave us, e.g. once it has left the network.
+    pub fn forget_peer(&mut self, addr: SocketAddr) {
+        self.peers.retain(|(a, _), _| *a != addr);
+    }
+
+    /// Drops every key for `channel`, e.g. after leaving the room.
+    pub fn forget_channel(&mut self, channel: &Channel) {
+        self.own.remove(channel);
+        self.peers.retain(|(_, c), _| c != channel);
+    }
+}
+
+fn associated_data(channel: &Channel, key_id: u32, iteration: u32) -> Vec<u8> {
+    let mut data = channel.as_deref().unwrap_or("").as_bytes().to_vec();
+    data.extend_from_slice(&key_id.to_be_bytes());
+    data.extend_from_slice(&iteration.to_be_bytes());
+    data
+}
@@ -0,0 +1,325 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const IDENTITY_FILE: &str = "identity.key";
+const KNOWN_PEERS_FILE: &str = "known_peers.json";
+const SAFETY_NUMBER_CONTEXT: &str = "rust-chat 2025 safety number";
+const INSTANCE_TAG_CONTEXT: &str = "rust-chat 2025 mdns instance tag";
+
+/// Long-term Ed25519 key that identifies this installation across sessions.
+pub struct Identity {
+    signing_key: SigningKey,
+}
+
+impl Identity {
+    /// Loads the identity key from `dir`, generating and saving one on first
+    /// run. A key that exists but cannot be read is an error, never replaced.
+    pub fn load_or_create(dir: &Path) -> std::io::Result<Self> {
+        let path = dir.join(IDENTITY_FILE);
+
+        match std::fs::read(&path) {
+            Ok(bytes) => {
+                let secret: [u8; 32] = bytes.try_into().map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Corrupt identity key at {}", path.display()),
+                    )
+                })?;
+                return Ok(Self {
+                    signing_key: SigningKey::from_bytes(&secret),
+                });
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let signing_key = SigningKey::generate(&mut rand::rngs::OsRng);
+        create_private_dir(dir)?;
+        write_private_file(&path, signing_key.as_bytes())?;
+
+        Ok(Self { signing_key })
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.signing_key.verifying_key().to_bytes()
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public_key())
+    }
+
+    pub fn sign(&self, data: &[u8]) -> [u8; 64] {
+        self.signing_key.sign(data).to_bytes()
+    }
+
+    /// Key for encrypting local state, bound to this identity and `context`.
+    pub fn derive_key(&self, context: &str) -> [u8; 32] {
+        blake3::derive_key(context, self.signing_key.as_bytes())
+    }
+
+    /// X25519 agreement between our identity key and `public`, for messages
+    /// sealed to us with [`exchange_key`]. `None` for a low-order point.
+    pub fn agree(&self, public: &[u8; 32]) -> Option<[u8; 32]> {
+        let secret = StaticSecret::from(self.signing_key.to_scalar_bytes());
+        let shared = secret.diffie_hellman(&PublicKey::from(*public));
+        shared.was_contributory().then(|| shared.to_bytes())
+    }
+}
+
+/// The X25519 form of an Ed25519 identity key, so that a message can be
+/// encrypted to a peer we have no session with.
+pub fn exchange_key(public_key: &[u8; 32]) -> Option<[u8; 32]> {
+    let key = VerifyingKey::from_bytes(public_key).ok()?;
+    Some(key.to_montgomery().to_bytes())
+}
+
+/// Checks an Ed25519 signature made by `public_key`.
+pub fn verify(public_key: &[u8; 32], data: &[u8], signature: &[u8]) -> bool {
+    let Ok(key) = VerifyingKey::from_bytes(public_key) else {
+        return false;
+    };
+    let Ok(signature) = Signature::from_slice(signature) else {
+        return false;
+    };
+    key.verify(data, &signature).is_ok()
+}
+
+/// The BLAKE3 digest behind [`fingerprint`], as carried in invites.
+pub fn fingerprint_digest(public_key: &[u8; 32]) -> [u8; 16] {
+    let hash = blake3::hash(public_key);
+    hash.as_bytes()[..16].try_into().unwrap()
+}
+
+/// Short, human-readable BLAKE3 digest of an identity key, e.g. `3f2a 9c1d ...`.
+pub fn fingerprint(public_key: &[u8; 32]) -> String {
+    hex::encode(fingerprint_digest(public_key))
+        .as_bytes()
+        .chunks(4)
+        .map(|group| std::str::from_utf8(group).unwrap())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The id advertised with an mDNS instance name, tying it to the identity
+/// key behind it. Telling whose it is takes the key, so only peers that
+/// have met us before can, and it changes with every instance name.
+pub fn instance_tag(public_key: &[u8; 32], instance: &str) -> String {
+    let mut material = public_key.to_vec();
+    material.extend_from_slice(instance.as_bytes());
+    hex::encode(&blake3::derive_key(INSTANCE_TAG_CONTEXT, &material)[..16])
+}
+
+/// Digits both parties read aloud to confirm they hold each other's real
+/// keys. The keys are sorted so each side computes the same number.
+pub fn safety_number(a: &[u8; 32], b: &[u8; 32]) -> String {
+    let (first, second) = if a <= b { (a, b) } else { (b, a) };
+    let mut material = Vec::with_capacity(64);
+    material.extend_from_slice(first);
+    material.extend_from_slice(second);
+
+    let digest = blake3::derive_key(SAFETY_NUMBER_CONTEXT, &material);
+    digest
+        .chunks(5)
+        .take(6)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+            format!("{:05}", value % 100_000)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub enum Trust {
+    /// First time this name has been seen; its key is now pinned.
+    New,
+    /// The key matches the one pinned for this name.
+    Known,
+    /// The name is pinned to a different key.
+    Mismatch { pinned: String },
+}
+
+#[derive(Serialize, Deserialize)]
+struct PinnedPeer {
+    key: String,
+    #[serde(default)]
+    verified: bool,
+}
+
+/// Trust-on-first-use store mapping display names to identity keys.
+pub struct TrustStore {
+    path: PathBuf,
+    pins: HashMap<String, PinnedPeer>,
+    /// Key each name most recently presented this run, which may differ from its pin.
+    seen: HashMap<String, [u8; 32]>,
+    /// Key whose safety number was last shown for each name, which is the
+    /// one a confirmation verifies.
+    shown: HashMap<String, [u8; 32]>,
+}
+
+impl TrustStore {
+    /// Loads the pins from `dir`, starting empty on first run. A file that
+    /// cannot be read is an error rather than no pins, as saving over it
+    /// would lose them all.
+    pub fn load(dir: &Path) -> std::io::Result<Self> {
+        let path = dir.join(KNOWN_PEERS_FILE);
+        let pins = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Corrupt known peers at {}: {}", path.display(), e),
+                )
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(Self {
+            path,
+            pins,
+            seen: HashMap::new(),
+            shown: HashMap::new(),
+        })
+    }
+
+    /// Compares `key` against the pin for `name`, pinning it if unknown.
+    pub fn check(&mut self, name: &str, key: &[u8; 32]) -> Trust {
+        self.seen.insert(name.to_string(), *key);
+
+        match self.pins.get(name) {
+            Some(pinned) if pinned.key == hex::encode(key) => Trust::Known,
+            Some(pinned) => Trust::Mismatch {
+                pinned: pinned_fingerprint(&pinned.key),
+            },
+            None => {
+                self.pins.insert(
+                    name.to_string(),
+                    PinnedPeer {
+                        key: hex::encode(key),
+                        verified: false,
+                    },
+                );
+                self.save_or_warn();
+                Trust::New
+            }
+        }
+    }
+
+    /// The key `name` is currently using: the one seen this run, else its pin.
+    pub fn current_key(&self, name: &str) -> Option<[u8; 32]> {
+        self.seen.get(name).copied().or_else(|| {
+            let pinned = self.pins.get(name)?;
+            hex::decode(&pinned.key).ok()?.try_into().ok()
+        })
+    }
+
+    /// The key `name` is currently using, remembered as the one the user is
+    /// about to compare in person.
+    pub fn show(&mut self, name: &str) -> Option<[u8; 32]> {
+        let key = self.current_key(name)?;
+        self.shown.insert(name.to_string(), key);
+        Some(key)
+    }
+
+    /// Pins the key last shown for `name` as verified in person. Whatever
+    /// key the name has presented since, it is the shown one the user
+    /// compared.
+    pub fn mark_verified(&mut self, name: &str) -> Option<[u8; 32]> {
+        let key = self.shown.remove(name)?;
+        self.pins.insert(
+            name.to_string(),
+            PinnedPeer {
+                key: hex::encode(key),
+                verified: true,
+            },
+        );
+        self.save_or_warn();
+        Some(key)
+    }
+
+    pub fn is_verified(&self, name: &str, key: &[u8; 32]) -> bool {
+        self.pins
+            .get(name)
+            .is_some_and(|pinned| pinned.verified && pinned.key == hex::encode(key))
+    }
+
+    /// Names and fingerprints of every verified peer, sorted by name.
+    pub fn verified(&self) -> Vec<(String, String)> {
+        let mut verified: Vec<_> = self
+            .pins
+            .iter()
+            .filter(|(_, pinned)| pinned.verified)
+            .map(|(name, pinned)| (name.clone(), pinned_fingerprint(&pinned.key)))
+            .collect();
+        verified.sort();
+        verified
+    }
+
+    fn save_or_warn(&self) {
+        if let Err(e) = self.save() {
+            eprintln!("Failed to save known peers: {}", e);
+        }
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        let data = serde_json::to_vec_pretty(&self.pins)?;
+        if let Some(dir) = self.path.parent() {
+            create_private_dir(dir)?;
+        }
+        write_private_file(&self.path, &data)
+    }
+}
+
+fn pinned_fingerprint(key_hex: &str) -> String {
+    hex::decode(key_hex)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(|key: [u8; 32]| fingerprint(&key))
+        .unwrap_or_else(|| "<corrupt>".to_string())
+}
+
+/// Directory holding the identity key and pinned peers, `~/.rust-chat` by default.
+pub fn default_data_dir() -> PathBuf {
+    std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .unwrap_or_default()
+        .join(".rust-chat")
+}
+
+pub fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+/// Replaces `path` with `data`, readable only by us. The data goes to a
+/// temporary file that is renamed over `path`, so a crash midway leaves the
+/// old contents intact. The temporary file is private from the moment it
+/// exists.
+pub fn write_private_file(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let temp = path.with_extension("tmp");
+    // Left behind by a crash, and possibly with looser permissions.
+    if let Err(e) = std::fs::remove_file(&temp)
+        && e.kind() != std::io::ErrorKind::NotFound
+    {
+        return Err(e);
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&temp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    std::fs::rename(&temp, path)
+}
@@ -0,0 +1,118 @@
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use crate::identity;
+
+const INVITE_VERSION: u8 = 1;
+const PEERS_FILE: &str = "peers.txt";
+
+/// What someone pastes into another instance to peer with us where mDNS
+/// cannot find us: where we listen, and the fingerprint of our identity
+/// key, so that nobody else can answer in our place.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Invite {
+    version: u8,
+    pub addrs: Vec<SocketAddr>,
+    pub fingerprint: [u8; 16],
+}
+
+impl Invite {
+    pub fn new(addrs: Vec<SocketAddr>, public_key: &[u8; 32]) -> Self {
+        Self {
+            version: INVITE_VERSION,
+            addrs,
+            fingerprint: identity::fingerprint_digest(public_key),
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(bincode::serialize(self).expect("invite serializes"))
+    }
+
+    pub fn decode(text: &str) -> Result<Self, String> {
+        let data = URL_SAFE_NO_PAD
+            .decode(text)
+            .map_err(|_| "Not an address or invite")?;
+        let invite: Self = bincode::deserialize(&data).map_err(|_| "Damaged invite")?;
+        if invite.version != INVITE_VERSION {
+            return Err(format!("Unsupported invite version {}", invite.version));
+        }
+        if invite.addrs.is_empty() {
+            return Err("Invite has no addresses".to_string());
+        }
+        Ok(invite)
+    }
+}
+
+/// A peer to connect to by hand, from `--connect`, `/connect` or the
+/// peers file.
+#[derive(Clone, PartialEq)]
+pub enum Target {
+    /// `host:port`, resolved afresh on every attempt.
+    Address(String),
+    Invite(Invite),
+}
+
+impl Target {
+    /// Reads `host:port`, `[v6 address]:port` or an invite. Invites are
+    /// base64 and never contain a colon.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        match text.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                Ok(Target::Address(text.to_string()))
+            }
+            Some(_) => Err(format!("Expected host:port, got {}", text)),
+            None => Invite::decode(text).map(Target::Invite),
+        }
+    }
+
+    pub async fn resolve(&self) -> Result<Vec<SocketAddr>, String> {
+        match self {
+            Target::Address(address) => tokio::net::lookup_host(address.as_str())
+                .await
+                .map(Iterator::collect)
+                .map_err(|e| format!("Could not resolve {}: {}", address, e)),
+            Target::Invite(invite) => Ok(invite.addrs.clone()),
+        }
+    }
+
+    /// The identity the peer must prove, for invites.
+    pub fn fingerprint(&self) -> Option<[u8; 16]> {
+        match self {
+            Target::Address(_) => None,
+            Target::Invite(invite) => Some(invite.fingerprint),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Address(address) => write!(f, "{}", address),
+            Target::Invite(invite) => write!(f, "invite for {}", invite.addrs[0]),
+        }
+    }
+}
+
+/// Reads `peers.txt` from the data directory: one `host:port` or invite per
+/// line, with `#` starting a comment. A missing file means no peers.
+pub fn load_peers_file(dir: &Path) -> Result<Vec<Target>, String> {
+    let path = dir.join(PEERS_FILE);
+    let Ok(contents) = std::fs::read_to_string(&path) else {
+        return Ok(Vec::new());
+    };
+    contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.split('#').next().unwrap_or("").trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(i, line)| {
+            Target::parse(line).map_err(|e| format!("{} line {}: {}", path.display(), i + 1, e))
+        })
+        .collect()
+}
@@ -1,22 +1,63 @@
-use chacha20poly1305::{
-    aead::{Aead, KeyInit},
-    ChaCha20Poly1305, Nonce,
-};
-use clap::{Parser, arg, command};
+mod beacon;
+mod connection;
+mod frame;
+mod gossip;
+mod group;
+mod identity;
+mod invite;
+mod net;
+mod outbox;
+mod pake;
+mod peers;
+mod presence;
+mod ratchet;
+mod receipts;
+mod relay;
+mod rooms;
+mod session;
+mod transfer;
+
+use beacon::{BEACON_INTERVAL, Beacon, Beacons};
+use clap::Parser;
 use colored::Colorize;
 use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, mpsc};
+use tokio::time::{Duration, sleep};
+
+use connection::{
+    ConnectionEvent, ConnectionManager, HandshakeTimeout, TrafficPolicy, UnexpectedIdentity,
+};
+use frame::IncompatibleVersion;
+use group::{Channel, GroupCiphertext, GroupKeys, SenderKey};
+use identity::{Identity, Trust, TrustStore};
+use invite::{Invite, Target};
+use net::NetworkPolicy;
+use outbox::{Outbox, Queued};
+use pake::RoomKeys;
+use peers::{Peer, Peers};
+use presence::{HEARTBEAT_INTERVAL, PeerState};
+use receipts::{MessageId, Receipts, Seen, Status};
+use relay::{Envelope, Mesh, ROUTE_INTERVAL, RouteEntry};
+use rooms::Rooms;
+use session::{LocalPeer, NoHandshake};
+use transfer::{Audience, FileKind, TransferId, Transfers};
 
 const SERVICE_TYPE: &str = "_rustchat._tcp.local.";
+/// How often the mDNS instance name is replaced, so it cannot be used to
+/// follow a device around for long.
+const SERVICE_NAME_ROTATION: Duration = Duration::from_secs(10 * 60);
+/// How long the old instance name stays up alongside the new one.
+const SERVICE_NAME_OVERLAP: Duration = Duration::from_secs(5);
+/// TXT record key for the tag tying an instance name to its identity key.
+const INSTANCE_TAG: &str = "id";
 const NONCE_SIZE: usize = 12;
-const MAX_MESSAGE_SIZE: usize = 50 * 1024 * 1024; // 50MB max for videos
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024; // 1MB per frame; files are sent in chunks
 
 #[derive(Parser)]
 #[command(name = "rust-chat")]
@@ -29,69 +70,624 @@ struct Cli {
     /// Port to listen on (default: random)
     #[arg(short, long, value_name = "PORT")]
     port: Option<u16>,
+
+    /// Directory for the identity key and pinned peers (default: ~/.rust-chat)
+    #[arg(long, value_name = "DIR")]
+    data_dir: Option<PathBuf>,
+
+    /// Directory accepted files are saved to
+    #[arg(long, value_name = "DIR", default_value = "downloads")]
+    download_dir: PathBuf,
+
+    /// Room to join at startup, e.g. #ops
+    #[arg(long, value_name = "ROOM")]
+    room: Option<String>,
+
+    /// Passphrase for --room; only peers who know it can read the room
+    #[arg(long, value_name = "PASSWORD", requires = "room")]
+    room_password: Option<String>,
+
+    /// Pad every message to a size bucket so its length is hidden
+    #[arg(long)]
+    pad: bool,
+
+    /// Send each peer a dummy message every SECONDS on average (implies --pad)
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    cover_traffic: Option<u64>,
+
+    /// Don't tell senders when you have seen their messages
+    #[arg(long)]
+    no_read_receipts: bool,
+
+    /// Minutes to keep messages for peers that are away
+    #[arg(long, value_name = "MINUTES", default_value_t = 60)]
+    outbox_ttl: u64,
+
+    /// Keep the outbox on disk, encrypted, so it survives a restart
+    #[arg(long)]
+    persist_outbox: bool,
+
+    /// Forward encrypted messages between peers that cannot reach each other
+    #[arg(long)]
+    relay: bool,
+
+    /// Only use this network interface, e.g. eth0 (repeatable)
+    #[arg(long, value_name = "NAME")]
+    interface: Vec<String>,
+
+    /// Never use this network interface, e.g. docker0 (repeatable)
+    #[arg(long, value_name = "NAME")]
+    exclude_interface: Vec<String>,
+
+    /// Use IPv4 only: no IPv6 listening, discovery or connections
+    #[arg(long)]
+    ipv4_only: bool,
+
+    /// Also announce and find peers with UDP broadcasts on PORT (default 47474)
+    #[arg(long, value_name = "PORT", num_args = 0..=1, default_missing_value = "47474")]
+    beacon: Option<u16>,
+
+    /// Connect to a peer mDNS cannot find, by host:port or invite (repeatable)
+    #[arg(long, value_name = "ADDR", value_parser = Target::parse)]
+    connect: Vec<Target>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 enum MessageType {
     Text(String),
-    Image { filename: String, data: Vec<u8> },
-    Video { filename: String, data: Vec<u8> },
-    KeyExchange { public_key: Vec<u8> },
+    FileOffer {
+        transfer_id: TransferId,
+        filename: String,
+        size: u64,
+        hash: Vec<u8>,
+        kind: FileKind,
+        mime: String,
+    },
+    FileRequest {
+        transfer_id: TransferId,
+        offset: u64,
+    },
+    FileChunk {
+        transfer_id: TransferId,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    FileDeclined {
+        transfer_id: TransferId,
+    },
+    FileComplete {
+        transfer_id: TransferId,
+        verified: bool,
+    },
+    KeyExchange {
+        public_key: Vec<u8>,
+        /// Oldest protocol version the sender speaks; the newest is in the
+        /// frame header.
+        min_version: u8,
+    },
+    Identity {
+        public_key: Vec<u8>,
+        signature: Vec<u8>,
+        listen_port: u16,
+        ratchet_key: Vec<u8>,
+    },
+    /// SPAKE2 message for a password-protected room.
+    RoomPake {
+        room: String,
+        message: Vec<u8>,
+    },
+    /// Proof that the sender derived the same room key.
+    RoomConfirm {
+        room: String,
+        tag: Vec<u8>,
+    },
+    /// A sender key for a password-protected room, sealed under the
+    /// pairwise room key.
+    RoomSealed(Vec<u8>),
+    /// The sender's key for the message's channel.
+    SenderKey {
+        key_id: u32,
+        chain_key: Vec<u8>,
+        iteration: u32,
+    },
+    /// Asks a member to hand out its current sender key again.
+    SenderKeyRequest,
+    /// A channel message encrypted once for all members.
+    GroupMessage {
+        key_id: u32,
+        iteration: u32,
+        ciphertext: Vec<u8>,
+    },
+    /// Every room the sender is in, sent after each handshake and whenever
+    /// it joins or leaves one.
+    Rooms(Vec<String>),
+    /// The message with this id was received and shown.
+    Delivered {
+        id: MessageId,
+    },
+    /// The recipient has been at the keyboard since the message was shown.
+    Read {
+        id: MessageId,
+    },
+    /// A message for a peer the sender cannot reach directly, passed on by
+    /// relays.
+    Relayed(Box<Envelope>),
+    /// Peers a relay can reach, for sending them messages through it.
+    Routes(Vec<RouteEntry>),
+    /// A serialized group message from a big channel, passed from member to
+    /// member and signed by the member who wrote it.
+    Gossip {
+        origin: [u8; 32],
+        /// Times it may still be passed on; not covered by the signature.
+        hops: u8,
+        message: Vec<u8>,
+        signature: Vec<u8>,
+    },
+    /// Liveness check, answered with a `HeartbeatAck` carrying its nonce.
+    Heartbeat {
+        nonce: u64,
+    },
+    HeartbeatAck {
+        nonce: u64,
+    },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 struct Message {
+    id: MessageId,
     sender: String,
     msg_type: MessageType,
     timestamp: i64,
+    /// Set on direct messages, naming the single peer they were sent to.
+    recipient: Option<String>,
+    /// Room the message was posted in, or `None` for the lobby.
+    channel: Option<String>,
 }
 
-#[derive(Clone)]
-struct Peer {
-    name: String,
-    addr: SocketAddr,
+impl Message {
+    fn new(sender: String, msg_type: MessageType) -> Self {
+        Self {
+            id: rand::thread_rng().r#gen(),
+            sender,
+            msg_type,
+            timestamp: chrono::Utc::now().timestamp(),
+            recipient: None,
+            channel: None,
+        }
+    }
+}
+
+/// Outcome of a background fan-out, printed by the input loop.
+struct DeliveryReport {
+    what: String,
+    results: Vec<(String, Result<(), String>)>,
+}
+
+impl DeliveryReport {
+    fn print(&self) {
+        let failed: Vec<_> = self
+            .results
+            .iter()
+            .filter_map(|(name, result)| result.as_ref().err().map(|e| (name, e)))
+            .collect();
+        let delivered = self.results.len() - failed.len();
+
+        if failed.is_empty() {
+            println!(
+                "{}",
+                format!("{} sent to {} peer(s)", self.what, delivered).dimmed()
+            );
+            return;
+        }
+
+        println!(
+            "{} {} sent to {}/{} peer(s)",
+            "!".red().bold(),
+            self.what,
+            delivered,
+            self.results.len()
+        );
+        for (name, error) in failed {
+            println!("  {} {}: {}", "Failed:".red(), name.blue(), error);
+        }
+    }
+}
+
+/// Serialized messages to send one peer in order, or why there are none.
+type Payload = Result<Vec<Arc<[u8]>>, String>;
+
+/// mDNS instance name that says nothing about who we are.
+fn random_service_name() -> String {
+    format!("{:016x}", rand::thread_rng().r#gen::<u64>())
+}
+
+/// Short label for a message in delivery reports.
+fn describe(msg_type: &MessageType) -> String {
+    match msg_type {
+        MessageType::FileOffer { filename, .. } => filename.clone(),
+        _ => "Message".to_string(),
+    }
+}
+
+/// What a sent message was, for `/status`.
+fn summarize(msg_type: &MessageType) -> String {
+    match msg_type {
+        MessageType::Text(text) if text.chars().count() > 40 => {
+            format!("\"{}...\"", text.chars().take(40).collect::<String>())
+        }
+        MessageType::Text(text) => format!("\"{}\"", text),
+        _ => describe(msg_type),
+    }
 }
 
 struct ChatApp {
     name: String,
     port: u16,
-    peers: Arc<Mutex<HashMap<SocketAddr, Peer>>>,
-    encryption_key: Vec<u8>,
+    peers: Arc<Mutex<Peers>>,
+    identity: Arc<Identity>,
+    trust: Arc<Mutex<TrustStore>>,
+    connections: Arc<ConnectionManager>,
+    transfers: Arc<Transfers>,
+    rooms: Mutex<Rooms>,
+    room_keys: Mutex<RoomKeys>,
+    group_keys: Mutex<GroupKeys>,
+    receipts: Arc<Mutex<Receipts>>,
+    read_receipts: bool,
+    /// Messages for peers that went away, sent when they come back.
+    outbox: Arc<Mutex<Outbox>>,
+    /// Peers reachable only through relays.
+    mesh: Mutex<Mesh>,
+    /// Gossiped messages already handled, by author and id.
+    gossip_seen: Mutex<Seen<([u8; 32], MessageId)>>,
+    /// Random mDNS instance name; replaced every [`SERVICE_NAME_ROTATION`].
+    service_name: Mutex<String>,
+    mdns: Mutex<Option<ServiceDaemon>>,
+    network: NetworkPolicy,
+    /// Peers added by hand, redialled whenever they are away, with the
+    /// fingerprint of whoever answered there last (or the invite's).
+    manual: Mutex<Vec<(Target, Option<[u8; 16]>)>>,
+    /// Taken by `start_listener`, which owns the event loop.
+    events: Mutex<Option<mpsc::Receiver<ConnectionEvent>>>,
+    reports: mpsc::UnboundedSender<DeliveryReport>,
+    /// Taken by `handle_input`, which prints reports between prompts.
+    report_receiver: Mutex<Option<mpsc::UnboundedReceiver<DeliveryReport>>>,
 }
 
 impl ChatApp {
-    fn new(name: String, port: u16) -> Self {
-        let mut key = vec![0u8; 32];
-        rand::thread_rng().fill(&mut key[..]);
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        name: String,
+        port: u16,
+        identity: Identity,
+        trust: TrustStore,
+        download_dir: PathBuf,
+        policy: TrafficPolicy,
+        read_receipts: bool,
+        outbox: Outbox,
+        network: NetworkPolicy,
+    ) -> Self {
+        let identity = Arc::new(identity);
+        let (connections, events) = ConnectionManager::new(
+            LocalPeer {
+                identity: identity.clone(),
+                name: name.clone(),
+                listen_port: port,
+            },
+            policy,
+        );
+        let (reports, report_receiver) = mpsc::unbounded_channel();
+        let transfers = Transfers::new(name.clone(), download_dir, connections.clone());
 
         Self {
             name,
             port,
-            peers: Arc::new(Mutex::new(HashMap::new())),
-            encryption_key: key,
+            peers: Arc::new(Mutex::new(Peers::default())),
+            identity,
+            trust: Arc::new(Mutex::new(trust)),
+            connections,
+            transfers,
+            rooms: Mutex::new(Rooms::default()),
+            room_keys: Mutex::new(RoomKeys::default()),
+            group_keys: Mutex::new(GroupKeys::default()),
+            receipts: Arc::new(Mutex::new(Receipts::default())),
+            read_receipts,
+            outbox: Arc::new(Mutex::new(outbox)),
+            mesh: Mutex::new(Mesh::default()),
+            gossip_seen: Mutex::new(Seen::default()),
+            service_name: Mutex::new(random_service_name()),
+            mdns: Mutex::new(None),
+            network,
+            manual: Mutex::new(Vec::new()),
+            events: Mutex::new(events.into()),
+            reports,
+            report_receiver: Mutex::new(report_receiver.into()),
         }
     }
 
-    fn encrypt_message(&self, data: &[u8]) -> Result<Vec<u8>, String> {
-        let cipher = ChaCha20Poly1305::new_from_slice(&self.encryption_key)
-            .map_err(|e| format!("Cipher init error: {}", e))?;
+    async fn start_mdns_discovery(self: &Arc<Self>) -> Result<(), Box<dyn std::error::Error>> {
+        let mdns = ServiceDaemon::new()?;
+        self.network.configure(&mdns)?;
+        *self.mdns.lock().await = Some(mdns.clone());
+        self.advertise().await?;
+
+        let receiver = mdns.browse(SERVICE_TYPE)?;
+        let app = self.clone();
+
+        tokio::spawn(async move {
+            while let Ok(event) = receiver.recv_async().await {
+                match event {
+                    ServiceEvent::ServiceResolved(info) => {
+                        let instance = info.get_fullname().split('.').next().unwrap_or("Unknown");
+                        let tag = info.get_property_val_str(INSTANCE_TAG).map(str::to_string);
+                        let ips = info.get_addresses().iter().copied().collect();
+                        app.peer_found(instance, tag, ips, info.get_port()).await;
+                    }
+                    ServiceEvent::ServiceRemoved(_, fullname) => {
+                        let instance = fullname.split('.').next().unwrap_or("Unknown");
+
+                        // A peer we have a session with may only have
+                        // rotated its instance name, with the new one yet to
+                        // resolve, so its heartbeats decide when it is gone.
+                        let mut peers = app.peers.lock().await;
+                        let live = match peers.instance(instance) {
+                            Some(peer) => app.connections.is_connected(peer.addr).await,
+                            None => false,
+                        };
+                        // Dropping the peer changes channel membership, so
+                        // our sender keys are replaced before the next message.
+                        let removed = if live {
+                            None
+                        } else {
+                            peers.remove_instance(instance)
+                        };
+                        drop(peers);
+                        app.forget_peers(removed.into_iter().collect()).await;
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        let app = self.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(SERVICE_NAME_ROTATION).await;
+                if let Err(e) = app.rotate_service_name().await {
+                    eprintln!("{} {}", "Failed to rotate mDNS name:".red(), e);
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Records an instance announced by mDNS or a beacon and connects to
+    /// it, unless it is us or a peer online at one of its addresses.
+    async fn peer_found(
+        self: &Arc<Self>,
+        instance: &str,
+        tag: Option<String>,
+        ips: Vec<IpAddr>,
+        port: u16,
+    ) {
+        let own_tag = identity::instance_tag(&self.identity.public_key(), instance);
+        if tag.as_ref() == Some(&own_tag) {
+            return;
+        }
+        let addrs = self.network.candidates(ips, port);
+        if addrs.is_empty() {
+            return;
+        }
 
-        let mut nonce_bytes = [0u8; NONCE_SIZE];
-        rand::thread_rng().fill(&mut nonce_bytes);
-        let nonce = Nonce::from_slice(&nonce_bytes);
+        // The instance name is opaque and rotates, so it only tells us
+        // where to connect, and its tag which peer we already know it is.
+        // The peer's name and rooms arrive over the session.
+        let mut peers = self.peers.lock().await;
+        let peer = peers.discovered(instance, tag, addrs.clone());
+        if peer.presence.state() == PeerState::Online && addrs.contains(&peer.addr) {
+            return;
+        }
+        peer.presence.connecting();
+        drop(peers);
 
-        let ciphertext = cipher
-            .encrypt(nonce, data)
-            .map_err(|e| format!("Encryption error: {}", e))?;
+        let connections = self.connections.clone();
+        tokio::spawn(async move {
+            // Unreachable peers are retried when there is something to send.
+            match connections.connect_any(&addrs, None).await {
+                Err(e) if e.is::<IncompatibleVersion>() => println!(
+                    "{} {} {}",
+                    "Peer at".yellow(),
+                    addrs[0],
+                    format!("runs an {}", e).yellow()
+                ),
+                Err(e) if e.is::<HandshakeTimeout>() => {
+                    println!("{} {}", "Peer at".yellow(), e.to_string().yellow())
+                }
+                _ => {}
+            }
+        });
+    }
 
-        let mut result = nonce_bytes.to_vec();
-        result.extend_from_slice(&ciphertext);
-        Ok(result)
+    /// Cleans up after peers dropped from `peers`, because mDNS saw them go
+    /// or their heartbeats stopped.
+    async fn forget_peers(&self, removed: Vec<Peer>) {
+        let mut group_keys = self.group_keys.lock().await;
+        let mut outbox = self.outbox.lock().await;
+        let mut mesh = self.mesh.lock().await;
+        for peer in removed {
+            group_keys.forget_peer(peer.addr);
+            mesh.forget_via(peer.addr);
+            // Addresses we never got through to were never announced either.
+            if let Some((name, key)) = &peer.identity {
+                outbox.peer_left(*key, name.clone(), peer.addr, peer.rooms.clone());
+                println!("{} {}", "Peer left:".red(), name.blue());
+            }
+        }
     }
 
-    async fn start_mdns_discovery(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let mdns = ServiceDaemon::new()?;
-        let service_name = format!("{}-{}", self.name, rand::thread_rng().r#gen::<u32>());
+    /// Drops peers that have been silent too long and sends the rest a
+    /// heartbeat.
+    async fn check_presence(self: &Arc<Self>) {
+        let mut heartbeats = Vec::new();
+        let removed: Vec<Peer> = {
+            let mut peers = self.peers.lock().await;
+            for peer in peers.values_mut() {
+                match peer.presence.tick() {
+                    PeerState::Online | PeerState::Stale if peer.identity.is_some() => {
+                        heartbeats.push((peer.addr, peer.presence.ping()));
+                    }
+                    _ => {}
+                }
+            }
+            peers.remove_where(|peer| peer.presence.state() == PeerState::Gone)
+        };
+        self.forget_peers(removed).await;
+
+        for (addr, nonce) in heartbeats {
+            self.send_control(addr, None, vec![MessageType::Heartbeat { nonce }]);
+        }
+    }
+
+    /// Announces us with a UDP beacon on `port` every [`BEACON_INTERVAL`],
+    /// and treats beacons from others like mDNS announcements.
+    async fn start_beacon(self: &Arc<Self>, port: u16) -> Result<(), Box<dyn std::error::Error>> {
+        let beacons = Arc::new(Beacons::bind(port, self.network.ipv4_only)?);
+
+        let app = self.clone();
+        let sender = beacons.clone();
+        tokio::spawn(async move {
+            loop {
+                let instance = app.service_name.lock().await.clone();
+                let mut addrs: Vec<IpAddr> = app
+                    .network
+                    .local_addrs(app.port)
+                    .iter()
+                    .map(SocketAddr::ip)
+                    .collect();
+                addrs.truncate(beacon::MAX_ADDRS);
+                let beacon = Beacon {
+                    service: SERVICE_TYPE.to_string(),
+                    tag: identity::instance_tag(&app.identity.public_key(), &instance),
+                    instance,
+                    port: app.port,
+                    addrs,
+                };
+                sender
+                    .send(&beacon, &app.network.beacon_targets(port))
+                    .await;
+                sleep(BEACON_INTERVAL).await;
+            }
+        });
+
+        let app = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let (beacon, from) = beacons.recv().await;
+                if beacon.service != SERVICE_TYPE {
+                    continue;
+                }
+                let mut ips = beacon.addrs;
+                ips.push(from);
+                app.peer_found(&beacon.instance, Some(beacon.tag), ips, beacon.port)
+                    .await;
+            }
+        });
+
+        Ok(())
+    }
+
+    /// `/connect <host:port|invite>`: dials a peer mDNS cannot find and
+    /// keeps redialling it whenever it is away.
+    async fn add_manual(self: &Arc<Self>, input: &str) {
+        let target = match Target::parse(input) {
+            Ok(target) => target,
+            Err(e) => {
+                println!("{} {}", "Cannot connect:".yellow(), e);
+                return;
+            }
+        };
+        let mut manual = self.manual.lock().await;
+        if !manual.iter().any(|(known, _)| *known == target) {
+            manual.push((target.clone(), target.fingerprint()));
+        }
+        drop(manual);
+        println!("{} {}", "Connecting to".green(), target);
+        self.connect_manual(target, true);
+    }
+
+    /// Dials a peer added by hand, unless we already have a session with
+    /// it, on whatever address. Only says why it failed if `report` is set,
+    /// as redials run in the background.
+    fn connect_manual(self: &Arc<Self>, target: Target, report: bool) {
+        let app = self.clone();
+        tokio::spawn(async move {
+            let answered = app
+                .manual
+                .lock()
+                .await
+                .iter()
+                .find(|(known, _)| *known == target)
+                .and_then(|(_, fingerprint)| *fingerprint)
+                .or(target.fingerprint());
+            if let Some(fingerprint) = answered
+                && app.connections.is_connected_to(fingerprint).await
+            {
+                return;
+            }
+
+            let result = match target.resolve().await {
+                Ok(addrs) => {
+                    let addrs = app.network.order(addrs);
+                    app.connections
+                        .connect_any(&addrs, target.fingerprint())
+                        .await
+                }
+                Err(e) => Err(e.into()),
+            };
+            match result {
+                Ok((_, key)) => {
+                    let mut manual = app.manual.lock().await;
+                    if let Some((_, fingerprint)) =
+                        manual.iter_mut().find(|(known, _)| *known == target)
+                    {
+                        *fingerprint = Some(identity::fingerprint_digest(&key));
+                    }
+                }
+                Err(e) if report && e.is::<UnexpectedIdentity>() => {
+                    println!("{} {}", "WARNING:".red().bold(), e.to_string().red());
+                }
+                Err(e) if report => {
+                    println!("{} {}: {}", "Could not connect to".yellow(), target, e);
+                }
+                _ => {}
+            }
+        });
+    }
+
+    /// `/invite` prints a string another instance can pass to `/connect`
+    /// to reach us, even from another subnet.
+    fn show_invite(&self) {
+        let addrs = self.network.local_addrs(self.port);
+        if addrs.is_empty() {
+            println!("{}", "No network address to invite others to.".yellow());
+            return;
+        }
+        let invite = Invite::new(addrs, &self.identity.public_key());
+        println!(
+            "{}",
+            "Give this to a peer to paste after /connect (or --connect):".green()
+        );
+        println!("{}", invite.encode());
+    }
+
+    /// Registers our mDNS service under the current instance name, with a
+    /// tag only peers that know our identity key can match to us. Nothing
+    /// else is advertised: no name, no rooms.
+    async fn advertise(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(mdns) = self.mdns.lock().await.clone() else {
+            return Ok(());
+        };
+        let service_name = self.service_name.lock().await.clone();
 
         let service_info = ServiceInfo::new(
             SERVICE_TYPE,
@@ -99,244 +695,1620 @@ impl ChatApp {
             &format!("{}.local.", service_name),
             "",
             self.port,
-            None,
-        )?;
+            &[(
+                INSTANCE_TAG,
+                identity::instance_tag(&self.identity.public_key(), &service_name).as_str(),
+            )][..],
+        )?
+        .enable_addr_auto();
 
         mdns.register(service_info)?;
+        Ok(())
+    }
+
+    /// Re-advertises under a fresh instance name, then withdraws the old
+    /// one once peers have had a chance to see the new one.
+    async fn rotate_service_name(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let old = std::mem::replace(&mut *self.service_name.lock().await, random_service_name());
+        self.advertise().await?;
+
+        sleep(SERVICE_NAME_OVERLAP).await;
+        if let Some(mdns) = self.mdns.lock().await.clone() {
+            mdns.unregister(&format!("{}.{}", old, SERVICE_TYPE))?;
+        }
+        Ok(())
+    }
+
+    async fn start_listener(self: &Arc<Self>) -> Result<(), Box<dyn std::error::Error>> {
+        let listener = self.network.listen(self.port)?;
+        let connections = self.connections.clone();
+
+        tokio::spawn(async move {
+            loop {
+                if let Ok((socket, remote)) = listener.accept().await {
+                    let connections = connections.clone();
+
+                    tokio::spawn(async move {
+                        match connections.accept(socket).await {
+                            Ok(()) => {}
+                            Err(e) if e.is::<IncompatibleVersion>() => {
+                                println!(
+                                    "{} {} {}",
+                                    "Refused connection from".yellow(),
+                                    remote,
+                                    format!("({})", e).yellow()
+                                );
+                            }
+                            // A peer dialling all our addresses at once drops
+                            // the connections it did not need.
+                            Err(e) if e.is::<NoHandshake>() => {}
+                            Err(e) => eprintln!("Connection error: {}", e),
+                        }
+                    });
+                }
+            }
+        });
+
+        if let Some(events) = self.events.lock().await.take() {
+            tokio::spawn(self.clone().handle_events(events));
+        }
+
+        Ok(())
+    }
+
+    /// Processes handshakes and messages from every peer session.
+    async fn handle_events(self: Arc<Self>, mut events: mpsc::Receiver<ConnectionEvent>) {
+        while let Some(event) = events.recv().await {
+            match event {
+                ConnectionEvent::Connected {
+                    addr,
+                    peer_name,
+                    peer_key,
+                } => {
+                    Self::check_trust(&peer_name, &peer_key, &self.trust).await;
+                    let mut peers = self.peers.lock().await;
+                    let peer = peers.connected(addr, &peer_name, peer_key);
+                    let replaced = std::mem::replace(&mut peer.addr, addr);
+                    let discovered = peer.identity.is_none();
+                    peer.identity = Some((peer_name.clone(), peer_key));
+                    peer.presence.heard();
+                    drop(peers);
+
+                    // The session replaced one over another of the peer's
+                    // addresses, whose room keys went with it.
+                    if replaced != addr {
+                        self.room_keys.lock().await.forget_peer(replaced);
+                    }
+
+                    if discovered {
+                        println!("{} {}", "Discovered peer:".green(), peer_name.blue());
+                    }
+                    // Room keys are agreed per session, so the peer's answer
+                    // starts a fresh exchange for the rooms we share.
+                    let joined = self.rooms.lock().await.joined().iter().cloned().collect();
+                    self.send_control(addr, None, vec![MessageType::Rooms(joined)]);
+                    self.flush_outbox(addr, peer_key, peer_name).await;
+                    self.announce_routes().await;
+                }
+                ConnectionEvent::Message {
+                    addr,
+                    peer_key,
+                    message,
+                } => {
+                    if let Some(peer) = self.peers.lock().await.get_mut(&addr) {
+                        peer.presence.heard();
+                    }
+                    // A gossiped message is handled as if its author had
+                    // sent it to us.
+                    let (addr, peer_key, message) = if let MessageType::Gossip {
+                        origin,
+                        hops,
+                        message: data,
+                        signature,
+                    } = &message.msg_type
+                    {
+                        match self
+                            .receive_gossip(addr, *origin, *hops, data, signature)
+                            .await
+                        {
+                            Some((origin_addr, message)) => (origin_addr, *origin, message),
+                            None => continue,
+                        }
+                    } else {
+                        (addr, peer_key, message)
+                    };
+                    let Some(message) = self.open_channel_message(addr, message).await else {
+                        continue;
+                    };
+                    match &message.msg_type {
+                        MessageType::FileRequest { .. }
+                        | MessageType::FileChunk { .. }
+                        | MessageType::FileDeclined { .. }
+                        | MessageType::FileComplete { .. } => {
+                            self.transfers
+                                .handle(addr, &peer_key, &message, &message.sender)
+                                .await;
+                        }
+                        MessageType::FileOffer { .. } => {
+                            Self::check_trust(&message.sender, &peer_key, &self.trust).await;
+                            self.transfers
+                                .handle(addr, &peer_key, &message, &message.sender)
+                                .await;
+                            self.acknowledge(addr, &message).await;
+                        }
+                        MessageType::Delivered { id } => {
+                            self.record_receipt(addr, id, Status::Delivered).await;
+                        }
+                        MessageType::Read { id } => {
+                            self.record_receipt(addr, id, Status::Read).await;
+                        }
+                        MessageType::RoomPake {
+                            room,
+                            message: data,
+                        } => {
+                            self.answer_room_pake(addr, &peer_key, room, data).await;
+                        }
+                        MessageType::Rooms(rooms) => {
+                            if let Some(peer) = self.peers.lock().await.get_mut(&addr) {
+                                peer.rooms = rooms::parse_announced(rooms);
+                            }
+                            self.authenticate_rooms(addr).await;
+                        }
+                        MessageType::Heartbeat { nonce } => {
+                            let ack = MessageType::HeartbeatAck { nonce: *nonce };
+                            self.send_control(addr, None, vec![ack]);
+                        }
+                        MessageType::HeartbeatAck { nonce } => {
+                            if let Some(peer) = self.peers.lock().await.get_mut(&addr) {
+                                peer.presence.pong(*nonce);
+                            }
+                        }
+                        MessageType::Relayed(envelope) => {
+                            self.receive_envelope(addr, *envelope.clone()).await;
+                        }
+                        MessageType::Routes(entries) => {
+                            self.learn_routes(addr, entries.clone()).await;
+                        }
+                        MessageType::RoomConfirm { room, tag } => {
+                            let result = self
+                                .room_keys
+                                .lock()
+                                .await
+                                .confirm(addr, room, tag, &peer_key);
+                            match result {
+                                Ok(()) => {
+                                    println!(
+                                        "{} {} {}",
+                                        message.sender.blue(),
+                                        "knows the password for".green(),
+                                        room.cyan()
+                                    );
+                                    // Queued messages for the room were held
+                                    // until now.
+                                    self.flush_outbox(addr, peer_key, message.sender.clone())
+                                        .await;
+                                }
+                                Err(e) => println!(
+                                    "{} {} {}: {}",
+                                    message.sender.blue(),
+                                    "could not join".yellow(),
+                                    room.cyan(),
+                                    e
+                                ),
+                            }
+                        }
+                        MessageType::Text(_) => {
+                            Self::display_message(&message, Some(&peer_key), &self.trust).await;
+                            self.acknowledge(addr, &message).await;
+                        }
+                        _ => {
+                            Self::display_message(&message, Some(&peer_key), &self.trust).await;
+                        }
+                    }
+                }
+                ConnectionEvent::Replayed { addr, sequence } => {
+                    let name = match self.peers.lock().await.get(&addr) {
+                        Some(peer) => peer.display_name().to_string(),
+                        None => addr.to_string(),
+                    };
+                    println!(
+                        "{} {} {}",
+                        "WARNING: dropped a replayed message from".red().bold(),
+                        name.blue(),
+                        format!("(frame #{})", sequence).dimmed()
+                    );
+                }
+                ConnectionEvent::Disconnected { addr } => {
+                    if let Some(peer) = self.peers.lock().await.get_mut(&addr) {
+                        peer.presence.disconnected();
+                    }
+                    self.room_keys.lock().await.forget_peer(addr);
+                    self.mesh.lock().await.forget_via(addr);
+                    let transfers = self.transfers.clone();
+                    tokio::spawn(async move { transfers.resume(addr).await });
+                }
+            }
+        }
+    }
+
+    /// Prints a message. `peer_key` is the identity key of the session it
+    /// arrived on, or `None` for our own messages.
+    async fn display_message(
+        message: &Message,
+        peer_key: Option<&[u8; 32]>,
+        trust: &Arc<Mutex<TrustStore>>,
+    ) {
+        let time_str = chrono::DateTime::from_timestamp(message.timestamp, 0)
+            .map(|dt| dt.format("%H:%M:%S").to_string())
+            .unwrap_or_else(|| "??:??:??".to_string());
+
+        if let Some(peer_key) = peer_key {
+            Self::check_trust(&message.sender, peer_key, trust).await;
+        }
+
+        let who = match (&message.recipient, peer_key) {
+            (Some(recipient), None) => format!(
+                "{} -> {} {}",
+                message.sender.blue(),
+                recipient.blue(),
+                "(private)".magenta()
+            ),
+            (Some(_), Some(_)) => {
+                format!("{} -> you {}", message.sender.blue(), "(private)".magenta())
+            }
+            (None, _) => message.sender.blue().to_string(),
+        };
+        let who = match &message.channel {
+            Some(room) => format!("{} {}", room.cyan(), who),
+            None => who,
+        };
+
+        match &message.msg_type {
+            MessageType::Text(text) => {
+                println!("[{}] {}: {}", time_str.dimmed(), who, text);
+            }
+            MessageType::FileOffer {
+                filename,
+                size,
+                kind,
+                mime,
+                ..
+            } => {
+                println!(
+                    "[{}] {} offered {}: {} ({}, {})",
+                    time_str.dimmed(),
+                    who,
+                    kind.label(),
+                    filename.yellow(),
+                    mime,
+                    transfer::format_size(*size)
+                );
+            }
+            MessageType::KeyExchange { .. }
+            | MessageType::Identity { .. }
+            | MessageType::FileRequest { .. }
+            | MessageType::FileChunk { .. }
+            | MessageType::FileDeclined { .. }
+            | MessageType::FileComplete { .. }
+            | MessageType::RoomPake { .. }
+            | MessageType::RoomConfirm { .. }
+            | MessageType::RoomSealed(_)
+            | MessageType::Rooms(_)
+            | MessageType::Delivered { .. }
+            | MessageType::Read { .. }
+            | MessageType::SenderKey { .. }
+            | MessageType::SenderKeyRequest
+            | MessageType::GroupMessage { .. }
+            | MessageType::Relayed(_)
+            | MessageType::Routes(_)
+            | MessageType::Gossip { .. }
+            | MessageType::Heartbeat { .. }
+            | MessageType::HeartbeatAck { .. } => {}
+        }
+    }
+
+    /// Checks `key` against the pin for `name`, warning loudly on a mismatch.
+    async fn check_trust(name: &str, key: &[u8; 32], trust: &Arc<Mutex<TrustStore>>) {
+        let fingerprint = identity::fingerprint(key);
+        match trust.lock().await.check(name, key) {
+            Trust::Known => {}
+            Trust::New => {
+                println!(
+                    "{} {} {}",
+                    "Pinned new identity for".yellow(),
+                    name.blue(),
+                    fingerprint.dimmed()
+                );
+            }
+            Trust::Mismatch { pinned } => {
+                println!("{}", "!".repeat(60).red().bold());
+                println!(
+                    "{} {} {}",
+                    "WARNING: IDENTITY KEY CHANGED FOR".red().bold(),
+                    name.blue().bold(),
+                    "- POSSIBLE IMPERSONATION".red().bold()
+                );
+                println!("  {} {}", "Pinned:  ".red(), pinned);
+                println!("  {} {}", "Received:".red(), fingerprint);
+                println!("{}", "!".repeat(60).red().bold());
+            }
+        }
+    }
+
+    /// Sends to everyone in the current room, or to every peer from the lobby.
+    async fn broadcast_message(&self, msg_type: MessageType) {
+        let mut message = Message::new(self.name.clone(), msg_type);
+        message.channel = self.rooms.lock().await.current().map(str::to_string);
+
+        let targets = self.channel_members(&message.channel).await;
+        let absent = self.queue_for_absent(&message, &targets).await;
+        // Files are fetched over a direct session, so only text is relayed.
+        let relayed = match message.msg_type {
+            MessageType::Text(_) => self.relayed_members(&message.channel).await,
+            _ => Vec::new(),
+        };
+        if targets.is_empty()
+            && absent.is_empty()
+            && relayed.is_empty()
+            && let Some(room) = &message.channel
+        {
+            println!("{} {}", "No one else is in".yellow(), room.cyan());
+        }
+
+        let mut tracked = targets.clone();
+        tracked.extend(absent.iter().cloned());
+        let what = self.track_sent(&message, &tracked).await;
+        self.relay(&message, relayed, &what).await;
+        self.deliver_group(&message, targets, what).await;
+
+        Self::display_message(&message, None, &self.trust).await;
+        if !absent.is_empty() {
+            let names: Vec<_> = absent.into_iter().map(|(_, name)| name).collect();
+            println!(
+                "{}",
+                format!("Queued for {} until they are back", names.join(", ")).dimmed()
+            );
+        }
+    }
+
+    /// Queues `message` for peers that left while in its channel, so they
+    /// get it when they return. Returns their last address and name.
+    async fn queue_for_absent(
+        &self,
+        message: &Message,
+        targets: &HashMap<SocketAddr, String>,
+    ) -> Vec<(SocketAddr, String)> {
+        let mut outbox = self.outbox.lock().await;
+        let absent = outbox.absent_members(&message.channel);
+        if absent.is_empty() {
+            return Vec::new();
+        }
+        let data = match bincode::serialize(message) {
+            Ok(data) => data,
+            Err(e) => {
+                eprintln!("{} {}", "Failed to encode message:".red(), e);
+                return Vec::new();
+            }
+        };
+
+        let mut queued = Vec::new();
+        for (key, addr, name) in absent {
+            if targets.contains_key(&addr) {
+                continue;
+            }
+            outbox.queue(
+                key,
+                Queued::new(message.id, data.clone(), message.channel.clone()),
+            );
+            queued.push((addr, name));
+        }
+        queued
+    }
+
+    /// Peers a message to `channel` goes to. In a password-protected room
+    /// that is only those who proved they know the password.
+    async fn channel_members(&self, channel: &Channel) -> HashMap<SocketAddr, String> {
+        let password_room = match channel {
+            Some(room) => self.rooms.lock().await.password(room).is_some(),
+            None => false,
+        };
+        let room_keys = self.room_keys.lock().await;
+
+        self.peers
+            .lock()
+            .await
+            .values()
+            .filter(|peer| match channel {
+                Some(room) => {
+                    peer.rooms.contains(room)
+                        && (!password_room || room_keys.is_confirmed(peer.addr, room))
+                }
+                None => true,
+            })
+            .map(|peer| (peer.addr, peer.display_name().to_string()))
+            .collect()
+    }
+
+    /// Identity keys of the peers a message to `channel` goes or would be
+    /// queued to.
+    async fn channel_keys(&self, channel: &Channel) -> HashSet<[u8; 32]> {
+        let members = self.channel_members(channel).await;
+        let mut keys: HashSet<[u8; 32]> = self
+            .peers
+            .lock()
+            .await
+            .values()
+            .filter(|peer| members.contains_key(&peer.addr))
+            .filter_map(|peer| Some(peer.identity.as_ref()?.1))
+            .collect();
+        let absent = self.outbox.lock().await.absent_members(channel);
+        keys.extend(absent.into_iter().map(|(key, _, _)| key));
+        keys
+    }
+
+    /// Peers only reachable through relays that a message to `channel` goes
+    /// to, with the relay to hand it to. Password-protected rooms are never
+    /// relayed, as members must prove the password over a direct session.
+    async fn relayed_members(&self, channel: &Channel) -> Vec<([u8; 32], String, SocketAddr)> {
+        if let Some(room) = channel
+            && self.rooms.lock().await.password(room).is_some()
+        {
+            return Vec::new();
+        }
+        let direct: HashSet<[u8; 32]> = self
+            .peers
+            .lock()
+            .await
+            .values()
+            .filter_map(|peer| Some(peer.identity.as_ref()?.1))
+            .collect();
+
+        self.mesh
+            .lock()
+            .await
+            .reachable()
+            .into_iter()
+            .filter(|(key, route)| {
+                !direct.contains(key)
+                    && channel
+                        .as_ref()
+                        .is_none_or(|room| route.rooms.contains(room))
+            })
+            .map(|(key, route)| (key, route.name.clone(), route.via))
+            .collect()
+    }
+
+    /// Seals `message` for each peer in `recipients` and hands the
+    /// envelopes to the first relay on their way, reporting per relay.
+    async fn relay(
+        &self,
+        message: &Message,
+        recipients: Vec<([u8; 32], String, SocketAddr)>,
+        what: &str,
+    ) {
+        if recipients.is_empty() {
+            return;
+        }
+        let data = match bincode::serialize(message) {
+            Ok(data) => data,
+            Err(e) => {
+                eprintln!("{} {}", "Failed to encode message:".red(), e);
+                return;
+            }
+        };
+
+        let mut by_relay: HashMap<SocketAddr, (Vec<String>, Payload)> = HashMap::new();
+        for (key, name, via) in recipients {
+            let envelope =
+                Envelope::seal(&self.identity, key, message.id, &data).and_then(|envelope| {
+                    let relayed =
+                        Message::new(self.name.clone(), MessageType::Relayed(Box::new(envelope)));
+                    bincode::serialize(&relayed).map_err(|e| e.to_string())
+                });
+            let (names, payload) = by_relay
+                .entry(via)
+                .or_insert_with(|| (Vec::new(), Ok(Vec::new())));
+            names.push(name);
+            match (payload, envelope) {
+                (Ok(frames), Ok(envelope)) => frames.push(envelope.into()),
+                (payload, Err(e)) => *payload = Err(e),
+                (Err(_), Ok(_)) => {}
+            }
+        }
+
+        let peers = self.peers.lock().await;
+        let payloads = by_relay
+            .into_iter()
+            .map(|(via, (names, payload))| {
+                let relay = peers
+                    .get(&via)
+                    .map_or_else(|| via.to_string(), |peer| peer.display_name().to_string());
+                (via, format!("{} via {}", names.join(", "), relay), payload)
+            })
+            .collect();
+        drop(peers);
+        self.send_payloads(format!("{} (relayed)", what), payloads, None);
+    }
+
+    /// Sends a direct message that only `peer` receives.
+    async fn send_to_peer(&self, peer: &Peer, msg_type: MessageType) {
+        let mut message = Message::new(self.name.clone(), msg_type);
+        message.recipient = Some(peer.display_name().to_string());
+
+        let targets = HashMap::from([(peer.addr, peer.display_name().to_string())]);
+        let what = self.track_sent(&message, &targets).await;
+        self.deliver(&message, targets, what);
+
+        Self::display_message(&message, None, &self.trust).await;
+    }
+
+    /// Sends a direct message to a peer only reachable through a relay.
+    /// Relayed messages get no receipts, so they are not tracked.
+    async fn send_relayed(&self, recipient: ([u8; 32], String, SocketAddr), msg_type: MessageType) {
+        let mut message = Message::new(self.name.clone(), msg_type);
+        message.recipient = Some(recipient.1.clone());
+
+        let what = describe(&message.msg_type);
+        self.relay(&message, vec![recipient], &what).await;
+
+        Self::display_message(&message, None, &self.trust).await;
+    }
+
+    /// Sends to every target in the background so the prompt returns at once;
+    /// the per-peer outcome comes back to `handle_input` as a report.
+    fn deliver(&self, message: &Message, targets: HashMap<SocketAddr, String>, what: String) {
+        if targets.is_empty() {
+            return;
+        }
+
+        let serialized: Arc<[u8]> = match bincode::serialize(message) {
+            Ok(data) => data.into(),
+            Err(e) => {
+                eprintln!("{} {}", "Failed to encode message:".red(), e);
+                return;
+            }
+        };
+
+        let fallback = Queued::new(message.id, serialized.to_vec(), message.channel.clone());
+        let payloads = targets
+            .into_iter()
+            .map(|(addr, name)| (addr, name, Ok(vec![serialized.clone()])))
+            .collect();
+        self.send_payloads(what, payloads, Some(fallback));
+    }
+
+    /// Encrypts a channel message once under our sender key and sends it to
+    /// every member, first handing the key to members who do not have it.
+    async fn deliver_group(
+        &self,
+        message: &Message,
+        targets: HashMap<SocketAddr, String>,
+        what: String,
+    ) {
+        if targets.is_empty() {
+            return;
+        }
+
+        let (inner, plain) = match (
+            bincode::serialize(&message.msg_type),
+            bincode::serialize(message),
+        ) {
+            (Ok(inner), Ok(plain)) => (inner, plain),
+            (Err(e), _) | (_, Err(e)) => {
+                eprintln!("{} {}", "Failed to encode message:".red(), e);
+                return;
+            }
+        };
+
+        let members = targets.keys().copied().collect();
+        let (new_key, encrypted) = {
+            let mut group_keys = self.group_keys.lock().await;
+            let new_key = group_keys.prepare(&message.channel, &members);
+            (new_key, group_keys.encrypt(&message.channel, &inner))
+        };
+        let encrypted = match encrypted {
+            Ok(encrypted) => encrypted,
+            Err(e) => {
+                eprintln!("{} {}", "Failed to encrypt message:".red(), e);
+                return;
+            }
+        };
+
+        let group_message = Message {
+            id: message.id,
+            sender: message.sender.clone(),
+            msg_type: MessageType::GroupMessage {
+                key_id: encrypted.key_id,
+                iteration: encrypted.iteration,
+                ciphertext: encrypted.ciphertext,
+            },
+            timestamp: message.timestamp,
+            recipient: None,
+            channel: message.channel.clone(),
+        };
+        let serialized: Arc<[u8]> = match bincode::serialize(&group_message) {
+            Ok(data) => data.into(),
+            Err(e) => {
+                eprintln!("{} {}", "Failed to encode message:".red(), e);
+                return;
+            }
+        };
+
+        // In a big channel whose members all hold our key already, a few of
+        // them get the message and pass it on.
+        if new_key.is_none() && targets.len() > gossip::THRESHOLD {
+            self.gossip(message.id, &serialized, targets, what).await;
+            return;
+        }
+
+        let mut payloads = Vec::new();
+        for (addr, name) in targets {
+            let mut data = Vec::new();
+            if let Some(key) = &new_key {
+                let handout = self
+                    .sender_key_message(addr, &message.channel, key)
+                    .await
+                    .and_then(|msg_type| {
+                        let mut handout = Message::new(self.name.clone(), msg_type);
+                        handout.channel = message.channel.clone();
+                        bincode::serialize(&handout).map_err(|e| e.to_string())
+                    });
+                match handout {
+                    Ok(handout) => data.push(handout.into()),
+                    Err(e) => {
+                        payloads.push((addr, name, Err(e)));
+                        continue;
+                    }
+                }
+            }
+            data.push(serialized.clone());
+            payloads.push((addr, name, Ok(data)));
+        }
+
+        // Sender keys change while a peer is away, so it gets queued
+        // messages over its session instead.
+        let fallback = Queued::new(message.id, plain, message.channel.clone());
+        self.send_payloads(what, payloads, Some(fallback));
+    }
+
+    /// Starts gossiping a serialized group message among `targets`.
+    async fn gossip(
+        &self,
+        id: MessageId,
+        group_message: &[u8],
+        targets: HashMap<SocketAddr, String>,
+        what: String,
+    ) {
+        let origin = self.identity.public_key();
+        self.gossip_seen.lock().await.insert((origin, id));
+
+        let rumor = MessageType::Gossip {
+            origin,
+            hops: gossip::MAX_HOPS,
+            message: group_message.to_vec(),
+            signature: gossip::sign(&self.identity, group_message),
+        };
+        let data: Arc<[u8]> = match bincode::serialize(&Message::new(self.name.clone(), rumor)) {
+            Ok(data) => data.into(),
+            Err(e) => {
+                eprintln!("{} {}", "Failed to encode message:".red(), e);
+                return;
+            }
+        };
+
+        let what = format!("{} (gossip to {} members)", what, targets.len());
+        let payloads = gossip::pick(self.connected(targets.keys().copied()).await)
+            .into_iter()
+            .map(|addr| (addr, targets[&addr].clone(), Ok(vec![data.clone()])))
+            .collect();
+        self.send_payloads(what, payloads, None);
+
+        // A rumour misses the odd member, so whoever has not confirmed
+        // delivery after a while gets a copy straight from us. With no hops
+        // left it goes no further, and members who had it drop it as seen.
+        let repair = MessageType::Gossip {
+            origin,
+            hops: 0,
+            message: group_message.to_vec(),
+            signature: gossip::sign(&self.identity, group_message),
+        };
+        let repair: Arc<[u8]> = match bincode::serialize(&Message::new(self.name.clone(), repair)) {
+            Ok(data) => data.into(),
+            Err(_) => return,
+        };
+        let receipts = self.receipts.clone();
+        let connections = self.connections.clone();
+        tokio::spawn(async move {
+            sleep(gossip::REPAIR_AFTER).await;
+            let missed = receipts.lock().await.pending(&id);
+            let sends = missed
+                .into_iter()
+                .filter(|addr| targets.contains_key(addr))
+                .map(|addr| (addr, vec![repair.clone()]))
+                .collect();
+            connections.fan_out(sends).await;
+        });
+    }
+
+    /// Checks a gossiped group message that came from `addr` and passes it
+    /// on to a few more members. Unless we had it already, or have never had
+    /// a session with its author, returns it with the author's address and
+    /// authenticated name, to be handled as if it came straight from them.
+    async fn receive_gossip(
+        self: &Arc<Self>,
+        addr: SocketAddr,
+        origin: [u8; 32],
+        hops: u8,
+        data: &[u8],
+        signature: &[u8],
+    ) -> Option<(SocketAddr, Message)> {
+        if !gossip::verify(&origin, data, signature) {
+            return None;
+        }
+        let mut message: Message = bincode::deserialize(data).ok()?;
+        if !matches!(message.msg_type, MessageType::GroupMessage { .. }) {
+            return None;
+        }
+        if !self.gossip_seen.lock().await.insert((origin, message.id)) {
+            return None;
+        }
+
+        // The name inside is whatever the author chose; the one its key
+        // completed a handshake under is the one we show and pin.
+        let author = self
+            .peers
+            .lock()
+            .await
+            .values()
+            .find_map(|peer| match &peer.identity {
+                Some((name, key)) if *key == origin => Some((peer.addr, name.clone())),
+                _ => None,
+            });
+
+        if hops > 0 {
+            let origin_addr = author.as_ref().map(|(addr, _)| *addr);
+            let members = self
+                .channel_members(&message.channel)
+                .await
+                .into_keys()
+                .filter(|member| *member != addr && Some(*member) != origin_addr);
+            let rumor = MessageType::Gossip {
+                origin,
+                hops: hops - 1,
+                message: data.to_vec(),
+                signature: signature.to_vec(),
+            };
+            for next in gossip::pick(self.connected(members).await) {
+                self.send_control(next, None, vec![rumor.clone()]);
+            }
+        }
+
+        match author {
+            Some((origin_addr, name)) => {
+                message.sender = name;
+                Some((origin_addr, message))
+            }
+            None => {
+                println!(
+                    "{} {} {}",
+                    "Dropped a message from".yellow(),
+                    identity::fingerprint(&origin).blue(),
+                    "(we have not met them)".yellow()
+                );
+                None
+            }
+        }
+    }
+
+    /// The addresses among `addrs` that we have a session with.
+    async fn connected(&self, addrs: impl Iterator<Item = SocketAddr>) -> Vec<SocketAddr> {
+        let peers = self.peers.lock().await;
+        addrs
+            .filter(|addr| peers.get(addr).is_some_and(|peer| peer.identity.is_some()))
+            .collect()
+    }
+
+    /// Sends what the outbox holds for the peer now connected on `addr`.
+    /// Messages for rooms we have since left are dropped; those for
+    /// password-protected rooms wait until the peer proves the password.
+    async fn flush_outbox(self: &Arc<Self>, addr: SocketAddr, peer_key: [u8; 32], name: String) {
+        let queued = self.outbox.lock().await.peer_returned(&peer_key);
+        if queued.is_empty() {
+            return;
+        }
+
+        let app = self.clone();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            let mut sent = 0;
+            let mut queued = queued.into_iter();
+            while let Some(message) = queued.next() {
+                if let Some(room) = &message.channel {
+                    let rooms = app.rooms.lock().await;
+                    if !rooms.is_joined(room) {
+                        continue;
+                    }
+                    if rooms.password(room).is_some()
+                        && !app.room_keys.lock().await.is_confirmed(addr, room)
+                    {
+                        held.push(message);
+                        continue;
+                    }
+                }
+                // Before sending, so that the receipt finds the new address.
+                app.receipts
+                    .lock()
+                    .await
+                    .readdress(&message.id, &name, addr);
+                if app
+                    .connections
+                    .send(addr, message.data.clone().into())
+                    .await
+                    .is_err()
+                {
+                    held.push(message);
+                    held.extend(queued);
+                    break;
+                }
+                sent += 1;
+            }
+
+            app.outbox.lock().await.requeue(peer_key, held);
+            if sent > 0 {
+                println!(
+                    "{} {}",
+                    format!("Sent {} queued message(s) to", sent).green(),
+                    name.blue()
+                );
+            }
+        });
+    }
+
+    /// Starts tracking receipts for a message and returns its label for the
+    /// delivery report, which includes the number `/status` takes.
+    async fn track_sent(&self, message: &Message, targets: &HashMap<SocketAddr, String>) -> String {
+        let number =
+            self.receipts
+                .lock()
+                .await
+                .track(message.id, summarize(&message.msg_type), targets);
+        format!("{} #{}", describe(&message.msg_type), number)
+    }
+
+    /// Tells the sender a message arrived and, unless read receipts are
+    /// off, remembers to tell it once the user has seen it.
+    async fn acknowledge(self: &Arc<Self>, addr: SocketAddr, message: &Message) {
+        if self.read_receipts {
+            self.receipts.lock().await.mark_unread(addr, message.id);
+        }
+        self.send_control(addr, None, vec![MessageType::Delivered { id: message.id }]);
+    }
+
+    /// Sends read receipts for everything shown since the user last typed.
+    async fn send_read_receipts(self: &Arc<Self>) {
+        let mut by_peer: HashMap<SocketAddr, Vec<MessageType>> = HashMap::new();
+        for (addr, id) in self.receipts.lock().await.take_unread() {
+            by_peer
+                .entry(addr)
+                .or_default()
+                .push(MessageType::Read { id });
+        }
+        for (addr, receipts) in by_peer {
+            self.send_control(addr, None, receipts);
+        }
+    }
+
+    async fn record_receipt(&self, addr: SocketAddr, id: &MessageId, status: Status) {
+        let Some((number, name)) = self.receipts.lock().await.update(addr, id, status) else {
+            return;
+        };
+        let verb = match status {
+            Status::Read => "read by",
+            _ => "delivered to",
+        };
+        println!(
+            "{}",
+            format!("{} #{} {} {}", status.tick(), number, verb, name).dimmed()
+        );
+    }
+
+    /// `/status [n]` lists who has received message `n`, or the last one sent.
+    async fn show_status(&self, input: &str) {
+        let number = match input {
+            "" => None,
+            input => match input.trim_start_matches('#').parse() {
+                Ok(number) => Some(number),
+                Err(_) => {
+                    println!("{} /status [message number]", "Usage:".yellow());
+                    return;
+                }
+            },
+        };
+
+        let receipts = self.receipts.lock().await;
+        let Some(sent) = receipts.get(number) else {
+            println!("{}", "No such message.".yellow());
+            return;
+        };
+
+        println!(
+            "\n{} {}",
+            format!("Message #{}", sent.number).green().bold(),
+            sent.summary
+        );
+        if sent.recipients.is_empty() {
+            println!("  {}", "Sent to no one".dimmed());
+        }
+        for (name, status) in sent.recipients.values() {
+            let line = format!("  {} {} - {}", status.tick(), name, status.label());
+            match status {
+                Status::Pending => println!("{}", line.yellow()),
+                _ => println!("{}", line.green()),
+            }
+        }
+        println!();
+    }
+
+    /// Our sender key as a message for `addr`, sealed under the room key
+    /// in password-protected rooms.
+    async fn sender_key_message(
+        &self,
+        addr: SocketAddr,
+        channel: &Channel,
+        key: &SenderKey,
+    ) -> Result<MessageType, String> {
+        let msg_type = MessageType::SenderKey {
+            key_id: key.key_id,
+            chain_key: key.chain_key.to_vec(),
+            iteration: key.iteration,
+        };
+
+        match channel {
+            Some(room) if self.rooms.lock().await.password(room).is_some() => {
+                let data = bincode::serialize(&msg_type).map_err(|e| e.to_string())?;
+                let sealed = self.room_keys.lock().await.seal(addr, room, &data)?;
+                Ok(MessageType::RoomSealed(sealed))
+            }
+            _ => Ok(msg_type),
+        }
+    }
 
-        let receiver = mdns.browse(SERVICE_TYPE)?;
+    /// Fans out per-peer payloads in the background and reports the result.
+    /// Targets whose payload could not be built are reported as failed.
+    /// Those that could not be reached get `fallback` queued in the outbox
+    /// if it is given and we know who they are.
+    fn send_payloads(
+        &self,
+        what: String,
+        payloads: Vec<(SocketAddr, String, Payload)>,
+        fallback: Option<Queued>,
+    ) {
+        let connections = self.connections.clone();
+        let reports = self.reports.clone();
         let peers = self.peers.clone();
-        let my_port = self.port;
+        let outbox = self.outbox.clone();
 
         tokio::spawn(async move {
-            while let Ok(event) = receiver.recv_async().await {
-                match event {
-                    ServiceEvent::ServiceResolved(info) => {
-                        if let Some(addr) = info.get_addresses().iter().next() {
-                            let port = info.get_port();
-
-                            if port != my_port {
-                                let socket_addr = SocketAddr::new(*addr, port);
-                                let peer_name = info
-                                    .get_fullname()
-                                    .split('.')
-                                    .next()
-                                    .unwrap_or("Unknown")
-                                    .to_string();
-
-                                let mut peers_lock = peers.lock().await;
-                                if !peers_lock.contains_key(&socket_addr) {
-                                    peers_lock.insert(
-                                        socket_addr,
-                                        Peer {
-                                            name: peer_name.clone(),
-                                            addr: socket_addr,
-                                        },
-                                    );
-                                    drop(peers_lock);
-
-                                    println!(
-                                        "{} {}",
-                                        "Discovered peer:".green(),
-                                        peer_name.blue()
-                                    );
-                                }
-                            }
-                        }
-                    }
-                    ServiceEvent::ServiceRemoved(_, fullname) => {
-                        let peer_name = fullname.split('.').next().unwrap_or("Unknown");
-                        println!("{} {}", "Peer left:".red(), peer_name.blue());
+            let mut names = HashMap::new();
+            let mut sends = Vec::new();
+            let mut results = Vec::new();
+            for (addr, name, payload) in payloads {
+                match payload {
+                    Ok(data) => {
+                        names.insert(addr, name);
+                        sends.push((addr, data));
                     }
-                    _ => {}
+                    Err(e) => results.push((name, Err(e))),
                 }
             }
-        });
 
-        Ok(())
+            let sent = connections.fan_out(sends).await;
+            let peer_keys: HashMap<SocketAddr, [u8; 32]> = peers
+                .lock()
+                .await
+                .values()
+                .filter_map(|peer| Some((peer.addr, peer.identity.as_ref()?.1)))
+                .collect();
+            // A peer listed under several addresses only needs one copy.
+            let reached: BTreeSet<_> = sent
+                .iter()
+                .filter(|(_, result)| result.is_ok())
+                .filter_map(|(addr, _)| peer_keys.get(addr))
+                .collect();
+
+            for (addr, result) in &sent {
+                let result = match (result, &fallback, peer_keys.get(addr)) {
+                    (Err(e), Some(message), Some(key)) if !reached.contains(key) => {
+                        outbox.lock().await.queue(*key, message.clone());
+                        Err(format!("{} (queued)", e))
+                    }
+                    (result, _, _) => result.clone(),
+                };
+                results.push((names[addr].clone(), result));
+            }
+            let _ = reports.send(DeliveryReport { what, results });
+        });
     }
 
-    async fn start_listener(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let listener = TcpListener::bind(format!("0.0.0.0:{}", self.port)).await?;
-        let peers = self.peers.clone();
-        let encryption_key = self.encryption_key.clone();
-        let name = self.name.clone();
-
+    /// Sends protocol messages to one peer in order, without a report.
+    fn send_control(
+        self: &Arc<Self>,
+        addr: SocketAddr,
+        channel: Channel,
+        msg_types: Vec<MessageType>,
+    ) {
+        let app = self.clone();
         tokio::spawn(async move {
-            loop {
-                if let Ok((socket, addr)) = listener.accept().await {
-                    let peers = peers.clone();
-                    let encryption_key = encryption_key.clone();
-                    let name = name.clone();
-
-                    tokio::spawn(async move {
-                        if let Err(e) = Self::handle_connection(
-                            socket,
-                            addr,
-                            peers,
-                            encryption_key,
-                            name,
-                        )
-                        .await
-                        {
-                            eprintln!("Connection error: {}", e);
-                        }
-                    });
+            for msg_type in msg_types {
+                let mut message = Message::new(app.name.clone(), msg_type);
+                message.channel = channel.clone();
+                let Ok(data) = bincode::serialize(&message) else {
+                    return;
+                };
+                if app.connections.send(addr, data.into()).await.is_err() {
+                    // Let the next connection start the room exchange afresh.
+                    app.room_keys.lock().await.forget_peer(addr);
+                    return;
                 }
             }
         });
+    }
 
-        Ok(())
+    /// Tells every known peer which rooms we are now in, so they know which
+    /// room messages to send us.
+    async fn announce_rooms(self: &Arc<Self>) {
+        let joined: Vec<String> = self.rooms.lock().await.joined().iter().cloned().collect();
+        let addrs: Vec<SocketAddr> = self
+            .peers
+            .lock()
+            .await
+            .values()
+            .map(|peer| peer.addr)
+            .collect();
+        for addr in addrs {
+            self.send_control(addr, None, vec![MessageType::Rooms(joined.clone())]);
+        }
     }
 
-    async fn handle_connection(
-        mut socket: TcpStream,
-        _addr: SocketAddr,
-        peers: Arc<Mutex<HashMap<SocketAddr, Peer>>>,
-        encryption_key: Vec<u8>,
-        _my_name: String,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let cipher = ChaCha20Poly1305::new_from_slice(&encryption_key)
-            .map_err(|e| format!("Cipher init error: {}", e))?;
+    /// As a relay, tells every peer we are connected to which other peers it
+    /// can reach through us.
+    async fn announce_routes(self: &Arc<Self>) {
+        if !self.mesh.lock().await.is_relaying() {
+            return;
+        }
 
-        loop {
-            let len = match socket.read_u32().await {
-                Ok(l) => l as usize,
-                Err(_) => break,
+        let mut direct: Vec<(SocketAddr, RouteEntry)> = Vec::new();
+        for peer in self.peers.lock().await.values() {
+            let Some((name, key)) = &peer.identity else {
+                continue;
             };
+            direct.push((
+                peer.addr,
+                RouteEntry {
+                    name: name.clone(),
+                    key: *key,
+                    hops: 1,
+                    rooms: peer.rooms.iter().cloned().collect(),
+                },
+            ));
+        }
+        let mut entries: Vec<RouteEntry> = Vec::new();
+        for (_, entry) in &direct {
+            if !entries.iter().any(|known| known.key == entry.key) {
+                entries.push(entry.clone());
+            }
+        }
+
+        let mesh = self.mesh.lock().await;
+        for (addr, peer) in &direct {
+            let routes = mesh.announcement(&peer.key, *addr, &entries);
+            self.send_control(*addr, None, vec![MessageType::Routes(routes)]);
+        }
+    }
+
+    /// Stores the routes a relay announced, ignoring any that pair a name
+    /// with a key other than the one we know it by.
+    async fn learn_routes(&self, addr: SocketAddr, entries: Vec<RouteEntry>) {
+        let own_key = self.identity.public_key();
+        let entries = {
+            let trust = self.trust.lock().await;
+            entries
+                .into_iter()
+                .filter(|entry| {
+                    entry.key != own_key
+                        && trust
+                            .current_key(&entry.name)
+                            .is_none_or(|key| key == entry.key)
+                })
+                .collect()
+        };
+        self.mesh.lock().await.update(addr, entries);
+    }
 
-            if len == 0 || len > MAX_MESSAGE_SIZE {
-                break;
+    /// Handles an envelope from the peer at `addr`: shows it if it is for
+    /// us, else passes it on if we relay.
+    async fn receive_envelope(self: &Arc<Self>, addr: SocketAddr, envelope: Envelope) {
+        if !self.mesh.lock().await.first_sighting(&envelope) {
+            return;
+        }
+        if envelope.to != self.identity.public_key() {
+            self.forward_envelope(addr, envelope).await;
+            return;
+        }
+
+        let opened = envelope
+            .open(&self.identity)
+            .and_then(|data| bincode::deserialize::<Message>(&data).map_err(|e| e.to_string()));
+        let message = match opened {
+            Ok(message) if message.id == envelope.id => message,
+            Ok(_) => return,
+            Err(e) => {
+                println!(
+                    "{} {}",
+                    "WARNING: dropped a relayed message:".red().bold(),
+                    e
+                );
+                return;
+            }
+        };
+        if !matches!(message.msg_type, MessageType::Text(_)) {
+            return;
+        }
+        if let Some(room) = &message.channel {
+            let rooms = self.rooms.lock().await;
+            if !rooms.is_joined(room) || rooms.password(room).is_some() {
+                return;
             }
+        }
 
-            let mut encrypted_data = vec![0u8; len];
-            socket.read_exact(&mut encrypted_data).await?;
+        Self::display_message(&message, Some(&envelope.from), &self.trust).await;
+    }
 
-            if encrypted_data.len() < NONCE_SIZE {
-                continue;
+    /// Passes an envelope that came from `from` on towards its recipient:
+    /// straight to it if we are connected, else to the nearest other relay.
+    async fn forward_envelope(self: &Arc<Self>, from: SocketAddr, mut envelope: Envelope) {
+        if !self.mesh.lock().await.is_relaying() || envelope.hops == 0 {
+            return;
+        }
+        envelope.hops -= 1;
+
+        let direct = self
+            .peers
+            .lock()
+            .await
+            .values()
+            .find(|peer| {
+                peer.addr != from
+                    && peer
+                        .identity
+                        .as_ref()
+                        .is_some_and(|(_, key)| *key == envelope.to)
+            })
+            .map(|peer| peer.addr);
+        let next = match direct {
+            Some(addr) => Some(addr),
+            None if envelope.hops > 0 => self
+                .mesh
+                .lock()
+                .await
+                .best(&envelope.to, Some(from))
+                .map(|route| route.via),
+            None => None,
+        };
+
+        if let Some(next) = next {
+            self.send_control(next, None, vec![MessageType::Relayed(Box::new(envelope))]);
+        }
+    }
+
+    /// Starts the room key exchange with the peer at `addr` for every
+    /// password-protected room we are both in.
+    async fn authenticate_rooms(self: &Arc<Self>, addr: SocketAddr) {
+        let Some(peer_rooms) = self
+            .peers
+            .lock()
+            .await
+            .get(&addr)
+            .map(|peer| peer.rooms.clone())
+        else {
+            return;
+        };
+
+        let rooms = self.rooms.lock().await;
+        let mut room_keys = self.room_keys.lock().await;
+        let mut messages = Vec::new();
+        for room in peer_rooms {
+            if let Some(password) = rooms.password(&room)
+                && let Some(message) = room_keys.start(addr, &room, password)
+            {
+                messages.push(MessageType::RoomPake { room, message });
             }
+        }
+        drop(room_keys);
+        drop(rooms);
+
+        if !messages.is_empty() {
+            self.send_control(addr, None, messages);
+        }
+    }
 
-            let nonce = Nonce::from_slice(&encrypted_data[..NONCE_SIZE]);
-            let ciphertext = &encrypted_data[NONCE_SIZE..];
+    /// Completes a room key exchange the peer at `addr` started or answered.
+    /// Requests for rooms we have no password for are ignored.
+    async fn answer_room_pake(
+        self: &Arc<Self>,
+        addr: SocketAddr,
+        peer_key: &[u8; 32],
+        room: &str,
+        data: &[u8],
+    ) {
+        let rooms = self.rooms.lock().await;
+        let Some(password) = rooms.password(room) else {
+            return;
+        };
 
-            if let Ok(decrypted) = cipher.decrypt(nonce, ciphertext) {
-                if let Ok(message) = bincode::deserialize::<Message>(&decrypted) {
-                    Self::display_message(&message, &peers).await;
+        let result = self.room_keys.lock().await.receive(
+            addr,
+            room,
+            password,
+            data,
+            &self.identity.public_key(),
+            peer_key,
+        );
+        drop(rooms);
+
+        match result {
+            Ok((reply, tag)) => {
+                let mut messages = Vec::new();
+                if let Some(message) = reply {
+                    messages.push(MessageType::RoomPake {
+                        room: room.to_string(),
+                        message,
+                    });
                 }
+                messages.push(MessageType::RoomConfirm {
+                    room: room.to_string(),
+                    tag: tag.to_vec(),
+                });
+                self.send_control(addr, None, messages);
             }
+            Err(e) => eprintln!("{} {}", "Room key exchange failed:".red(), e),
         }
-
-        Ok(())
     }
 
-    async fn display_message(message: &Message, _peers: &Arc<Mutex<HashMap<SocketAddr, Peer>>>) {
-        let time_str = chrono::DateTime::from_timestamp(message.timestamp, 0)
-            .map(|dt| dt.format("%H:%M:%S").to_string())
-            .unwrap_or_else(|| "??:??:??".to_string());
+    /// Unwraps channel traffic: drops messages for rooms we are not in,
+    /// stores sender keys and decrypts group messages. In password-protected
+    /// rooms, sender keys are only accepted sealed under the room key, and
+    /// queued messages only from peers that proved the password.
+    async fn open_channel_message(
+        self: &Arc<Self>,
+        addr: SocketAddr,
+        mut message: Message,
+    ) -> Option<Message> {
+        let password_room = match &message.channel {
+            Some(room) => {
+                // A peer can still reach us with a stale view of our membership.
+                let rooms = self.rooms.lock().await;
+                if !rooms.is_joined(room) {
+                    return None;
+                }
+                rooms.password(room).is_some()
+            }
+            None => false,
+        };
+        let member = match &message.channel {
+            Some(room) if password_room => self.room_keys.lock().await.is_confirmed(addr, room),
+            _ => true,
+        };
 
         match &message.msg_type {
-            MessageType::Text(text) => {
-                println!(
-                    "[{}] {}: {}",
-                    time_str.dimmed(),
-                    message.sender.blue(),
-                    text
-                );
+            MessageType::RoomSealed(sealed) if password_room => {
+                let room = message.channel.as_deref()?;
+                let inner = self.room_keys.lock().await.open(addr, room, sealed).ok()?;
+                if let MessageType::SenderKey {
+                    key_id,
+                    chain_key,
+                    iteration,
+                } = bincode::deserialize(&inner).ok()?
+                {
+                    self.install_sender_key(addr, &message.channel, key_id, &chain_key, iteration)
+                        .await;
+                }
+                None
             }
-            MessageType::Image { filename, data } => {
-                println!(
-                    "[{}] {} sent image: {} ({} KB)",
-                    time_str.dimmed(),
-                    message.sender.blue(),
-                    filename.yellow(),
-                    data.len() / 1024
-                );
+            MessageType::SenderKey {
+                key_id,
+                chain_key,
+                iteration,
+            } if !password_room => {
+                self.install_sender_key(addr, &message.channel, *key_id, chain_key, *iteration)
+                    .await;
+                None
             }
-            MessageType::Video { filename, data } => {
-                println!(
-                    "[{}] {} sent video: {} ({} MB)",
-                    time_str.dimmed(),
-                    message.sender.blue(),
-                    filename.yellow(),
-                    data.len() / (1024 * 1024)
-                );
+            MessageType::SenderKeyRequest => {
+                let key = self
+                    .group_keys
+                    .lock()
+                    .await
+                    .current(&message.channel, addr)?;
+                let msg_type = self
+                    .sender_key_message(addr, &message.channel, &key)
+                    .await
+                    .ok()?;
+                self.send_control(addr, message.channel.clone(), vec![msg_type]);
+                None
+            }
+            MessageType::GroupMessage {
+                key_id,
+                iteration,
+                ciphertext,
+            } => {
+                let encrypted = GroupCiphertext {
+                    key_id: *key_id,
+                    iteration: *iteration,
+                    ciphertext: ciphertext.clone(),
+                };
+                let decrypted =
+                    self.group_keys
+                        .lock()
+                        .await
+                        .decrypt(addr, &message.channel, &encrypted);
+                match decrypted {
+                    Ok(Some(inner)) => {
+                        message.msg_type = bincode::deserialize(&inner).ok()?;
+                        match message.msg_type {
+                            MessageType::Text(_) | MessageType::FileOffer { .. } => Some(message),
+                            _ => None,
+                        }
+                    }
+                    Ok(None) => {
+                        // We joined after the sender handed out its key.
+                        let channel = message.channel.clone();
+                        self.send_control(addr, channel, vec![MessageType::SenderKeyRequest]);
+                        None
+                    }
+                    Err(e) => {
+                        println!(
+                            "{} {}: {}",
+                            "WARNING: dropped a group message from".red().bold(),
+                            message.sender.blue(),
+                            e
+                        );
+                        None
+                    }
+                }
             }
-            MessageType::KeyExchange { .. } => {}
+            // Room traffic only travels as group messages, except for
+            // messages queued while we were away, which the sender's
+            // outbox delivers over our own session.
+            MessageType::RoomSealed(_) | MessageType::SenderKey { .. } => None,
+            MessageType::Text(_) | MessageType::FileOffer { .. } if member => Some(message),
+            _ if message.channel.is_some() => None,
+            _ => Some(message),
         }
     }
 
-    async fn send_to_peer(
+    async fn install_sender_key(
         &self,
         addr: SocketAddr,
-        message: &Message,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut stream = TcpStream::connect(addr).await?;
-
-        let serialized = bincode::serialize(message)?;
-        let encrypted = self.encrypt_message(&serialized)?;
-
-        stream.write_u32(encrypted.len() as u32).await?;
-        stream.write_all(&encrypted).await?;
-        stream.flush().await?;
-
-        Ok(())
+        channel: &Channel,
+        key_id: u32,
+        chain_key: &[u8],
+        iteration: u32,
+    ) {
+        let Ok(chain_key) = chain_key.try_into() else {
+            return;
+        };
+        self.group_keys.lock().await.install(
+            addr,
+            channel,
+            SenderKey {
+                key_id,
+                chain_key,
+                iteration,
+            },
+        );
     }
 
-    async fn broadcast_message(&self, msg_type: MessageType) {
-        let message = Message {
-            sender: self.name.clone(),
-            msg_type: msg_type.clone(),
-            timestamp: chrono::Utc::now().timestamp(),
-        };
+    /// The peer called `name`, case-insensitively, if it is only reachable
+    /// through a relay, with the relay to go through. Peers we are connected
+    /// to come first.
+    async fn find_relayed(&self, name: &str) -> Option<([u8; 32], String, SocketAddr)> {
+        if self
+            .peers
+            .lock()
+            .await
+            .values()
+            .any(|peer| peer.display_name().eq_ignore_ascii_case(name))
+        {
+            return None;
+        }
+        let mut matches: Vec<_> = self
+            .relayed_members(&None)
+            .await
+            .into_iter()
+            .filter(|(_, relayed, _)| relayed.eq_ignore_ascii_case(name))
+            .collect();
+        match matches.len() {
+            1 => matches.pop(),
+            _ => None,
+        }
+    }
 
+    /// Looks up a peer by display name, case-insensitively.
+    async fn find_peer(&self, name: &str) -> Option<Peer> {
         let peers = self.peers.lock().await;
-        let peer_addrs: Vec<SocketAddr> = peers.keys().copied().collect();
-        drop(peers);
-
-        for addr in peer_addrs {
-            if let Err(e) = self.send_to_peer(addr, &message).await {
-                eprintln!("Failed to send to {}: {}", addr, e);
+        let matches: Vec<&Peer> = peers
+            .values()
+            .filter(|peer| {
+                peer.display_name().eq_ignore_ascii_case(name)
+                    || peer.name.eq_ignore_ascii_case(name)
+            })
+            .collect();
+
+        match matches.as_slice() {
+            [peer] => Some((*peer).clone()),
+            [] => {
+                println!("{} {}", "No peer named".yellow(), name.blue());
+                None
+            }
+            _ => {
+                println!(
+                    "{} {}{}",
+                    "More than one peer is called".yellow(),
+                    name.blue(),
+                    ", use the name shown by /peers".yellow()
+                );
+                None
             }
         }
-
-        Self::display_message(&message, &self.peers).await;
     }
 
-    async fn handle_input(&self) {
+    async fn handle_input(self: &Arc<Self>) {
         use tokio::io::{AsyncBufReadExt, BufReader};
 
         println!("\n{}", "Commands:".green().bold());
         println!("  {}  - Send a text message", "text <message>".cyan());
         println!("  {} - Send an image file", "/img <filepath>".cyan());
         println!("  {} - Send a video file", "/vid <filepath>".cyan());
+        println!("  {} - Send any file", "/file <filepath>".cyan());
+        println!(
+            "  {} - Private message to one peer",
+            "/msg <peer> <text>".cyan()
+        );
+        println!(
+            "  {} - Send a file to one peer",
+            "/send-to <peer> <file>".cyan()
+        );
+        println!(
+            "  {} - Join or switch to a room",
+            "/join #room [password]".cyan()
+        );
+        println!(
+            "  {}  - Leave a room (default: current)",
+            "/leave [#room]".cyan()
+        );
+        println!("  {}        - List rooms on the network", "/rooms".cyan());
+        println!("  {}   - Accept an incoming file", "/accept [n]".cyan());
+        println!("  {}   - Reject an incoming file", "/reject [n]".cyan());
+        println!(
+            "  {}   - Who has received a message (default: last)",
+            "/status [n]".cyan()
+        );
         println!("  {}        - List connected peers", "/peers".cyan());
+        println!(
+            "  {} - Connect to a peer by address or invite",
+            "/connect <addr>".cyan()
+        );
+        println!(
+            "  {}       - Show an invite for others to connect",
+            "/invite".cyan()
+        );
+        println!(
+            "  {} - Show a peer's safety number",
+            "/verify <name>".cyan()
+        );
+        println!("  {}     - List verified peers", "/verified".cyan());
         println!("  {}         - Exit the chat", "/quit".cyan());
         println!();
 
         let stdin = tokio::io::stdin();
         let reader = BufReader::new(stdin);
         let mut lines = reader.lines();
+        let mut reports = self.report_receiver.lock().await.take();
 
         loop {
-            print!("> ");
+            match self.rooms.lock().await.current() {
+                Some(room) => print!("{}> ", room),
+                None => print!("> "),
+            }
             std::io::Write::flush(&mut std::io::stdout()).unwrap();
 
-            match lines.next_line().await {
+            let line = tokio::select! {
+                line = lines.next_line() => line,
+                Some(report) = async { reports.as_mut()?.recv().await } => {
+                    println!();
+                    report.print();
+                    continue;
+                }
+            };
+
+            match line {
                 Ok(Some(input)) => {
                     let input = input.trim();
+                    // Typing anything means everything shown so far was seen.
+                    self.send_read_receipts().await;
 
                     if input.starts_with("/quit") {
                         break;
+                    } else if input.starts_with("/status") {
+                        let number = input.strip_prefix("/status").unwrap().trim();
+                        self.show_status(number).await;
                     } else if input.starts_with("/peers") {
                         self.list_peers().await;
+                    } else if input.starts_with("/connect") {
+                        let target = input.strip_prefix("/connect").unwrap().trim();
+                        if target.is_empty() {
+                            println!("{} /connect <host:port|invite>", "Usage:".yellow());
+                        } else {
+                            self.add_manual(target).await;
+                        }
+                    } else if input.starts_with("/invite") {
+                        self.show_invite();
+                    } else if input.starts_with("/verified") {
+                        self.list_verified().await;
+                    } else if input.starts_with("/verify ") {
+                        let args = input.strip_prefix("/verify ").unwrap().trim();
+                        self.verify_peer(args).await;
+                    } else if input.starts_with("/join ") {
+                        let room = input.strip_prefix("/join ").unwrap().trim();
+                        self.join_room(room).await;
+                    } else if input.starts_with("/leave") {
+                        let room = input.strip_prefix("/leave").unwrap().trim();
+                        self.leave_room(room).await;
+                    } else if input.starts_with("/rooms") {
+                        self.list_rooms().await;
+                    } else if input.starts_with("/accept") {
+                        let number = input.strip_prefix("/accept").unwrap().trim().parse().ok();
+                        self.transfers.accept(number).await;
+                    } else if input.starts_with("/reject") {
+                        let number = input.strip_prefix("/reject").unwrap().trim().parse().ok();
+                        self.transfers.reject(number).await;
                     } else if input.starts_with("/img ") {
                         let path = input.strip_prefix("/img ").unwrap().trim();
-                        self.send_file(path, true).await;
+                        self.send_file(path, FileKind::Image, None).await;
                     } else if input.starts_with("/vid ") {
                         let path = input.strip_prefix("/vid ").unwrap().trim();
-                        self.send_file(path, false).await;
+                        self.send_file(path, FileKind::Video, None).await;
+                    } else if input.starts_with("/file ") {
+                        let path = input.strip_prefix("/file ").unwrap().trim();
+                        self.send_file(path, FileKind::File, None).await;
+                    } else if input.starts_with("/msg ") {
+                        let args = input.strip_prefix("/msg ").unwrap().trim();
+                        match args.split_once(' ') {
+                            Some((name, text)) if !text.trim().is_empty() => {
+                                let text = MessageType::Text(text.trim().to_string());
+                                if let Some(recipient) = self.find_relayed(name).await {
+                                    self.send_relayed(recipient, text).await;
+                                } else if let Some(peer) = self.find_peer(name).await {
+                                    self.send_to_peer(&peer, text).await;
+                                }
+                            }
+                            _ => println!("{} /msg <peer> <text>", "Usage:".yellow()),
+                        }
+                    } else if input.starts_with("/send-to ") {
+                        let args = input.strip_prefix("/send-to ").unwrap().trim();
+                        match args.split_once(' ') {
+                            Some((name, path)) if !path.trim().is_empty() => {
+                                if let Some(peer) = self.find_peer(name).await {
+                                    self.send_file(path.trim(), FileKind::File, Some(&peer))
+                                        .await;
+                                }
+                            }
+                            _ => println!("{} /send-to <peer> <filepath>", "Usage:".yellow()),
+                        }
                     } else if !input.is_empty() {
-                        self.broadcast_message(MessageType::Text(input.to_string())).await;
+                        self.broadcast_message(MessageType::Text(input.to_string()))
+                            .await;
                     }
                 }
                 Ok(None) | Err(_) => break,
@@ -344,52 +2316,333 @@ impl ChatApp {
         }
     }
 
+    /// `/join #room [password]`. With a password, only peers who know it
+    /// can read or post in the room.
+    async fn join_room(self: &Arc<Self>, input: &str) {
+        let (input, password) = match input.split_once(' ') {
+            Some((room, password)) if !password.trim().is_empty() => {
+                (room, Some(password.trim().to_string()))
+            }
+            _ => (input, None),
+        };
+        let room = match rooms::parse_room(input) {
+            Ok(room) => room,
+            Err(e) => {
+                println!("{} {}", "Cannot join:".red(), e);
+                return;
+            }
+        };
+
+        let (newly_joined, protected) = {
+            let mut rooms = self.rooms.lock().await;
+            let newly_joined = !rooms.is_joined(&room);
+            let password_changed =
+                password.is_some() && rooms.password(&room) != password.as_deref();
+            if let Err(e) = rooms.join(&room, password) {
+                println!("{} {}", "Cannot join:".red(), e);
+                return;
+            }
+            if password_changed {
+                self.room_keys.lock().await.forget_room(&room);
+            }
+            (newly_joined, rooms.password(&room).is_some())
+        };
+
+        if newly_joined {
+            self.announce_rooms().await;
+        }
+
+        let members: Vec<SocketAddr> = self
+            .peers
+            .lock()
+            .await
+            .values()
+            .filter(|peer| peer.rooms.contains(&room))
+            .map(|peer| peer.addr)
+            .collect();
+        for addr in &members {
+            self.authenticate_rooms(*addr).await;
+        }
+
+        println!(
+            "{} {} ({} other member(s){})",
+            "Now chatting in".green(),
+            room.cyan(),
+            members.len(),
+            if protected {
+                ", password protected"
+            } else {
+                ""
+            }
+        );
+    }
+
+    /// Leaves the named room, or the current one when `input` is empty.
+    async fn leave_room(self: &Arc<Self>, input: &str) {
+        let room = if input.is_empty() {
+            match self.rooms.lock().await.current() {
+                Some(room) => room.to_string(),
+                None => {
+                    println!("{}", "You are in the lobby, not a room.".yellow());
+                    return;
+                }
+            }
+        } else {
+            match rooms::parse_room(input) {
+                Ok(room) => room,
+                Err(e) => {
+                    println!("{} {}", "Cannot leave:".red(), e);
+                    return;
+                }
+            }
+        };
+
+        let (left, current) = {
+            let mut rooms = self.rooms.lock().await;
+            let left = rooms.leave(&room);
+            (left, rooms.current().map(str::to_string))
+        };
+        if !left {
+            println!("{} {}", "You are not in".yellow(), room.cyan());
+            return;
+        }
+        self.room_keys.lock().await.forget_room(&room);
+        self.group_keys
+            .lock()
+            .await
+            .forget_channel(&Some(room.clone()));
+
+        self.announce_rooms().await;
+        println!("{} {}", "Left".green(), room.cyan());
+        if current.is_none() {
+            println!("{}", "Now chatting in the lobby".green());
+        }
+    }
+
+    /// Lists every room we are in or a peer advertises, with member counts.
+    async fn list_rooms(&self) {
+        let mut counts: std::collections::BTreeMap<String, usize> = self
+            .rooms
+            .lock()
+            .await
+            .joined()
+            .iter()
+            .map(|room| (room.clone(), 0))
+            .collect();
+        for peer in self.peers.lock().await.values() {
+            for room in &peer.rooms {
+                *counts.entry(room.clone()).or_default() += 1;
+            }
+        }
+
+        if counts.is_empty() {
+            println!("{}", "No rooms yet, create one with /join #name".yellow());
+            return;
+        }
+
+        let rooms = self.rooms.lock().await;
+        println!("\n{}", "Rooms:".green().bold());
+        for (room, peers) in counts {
+            let status = if rooms.current() == Some(room.as_str()) {
+                "(current)".green()
+            } else if rooms.is_joined(&room) {
+                "(joined)".green()
+            } else {
+                "".normal()
+            };
+            let protected = if rooms.password(&room).is_some() {
+                " (password)".dimmed()
+            } else {
+                "".normal()
+            };
+            println!(
+                "  {} - {} peer(s) {}{}",
+                room.cyan(),
+                peers,
+                status,
+                protected
+            );
+        }
+        println!();
+    }
+
     async fn list_peers(&self) {
+        let relayed = self.relayed_members(&None).await;
         let peers = self.peers.lock().await;
-        if peers.is_empty() {
+        if peers.is_empty() && relayed.is_empty() {
             println!("{}", "No peers connected yet.".yellow());
         } else {
             println!("\n{}", "Connected Peers:".green().bold());
+            let trust = self.trust.lock().await;
             for peer in peers.values() {
-                println!("  {} - {}", peer.name.blue(), peer.addr);
+                let status = match &peer.identity {
+                    Some((name, key)) if trust.is_verified(name, key) => {
+                        format!("{} (verified)", name).green()
+                    }
+                    Some((name, _)) => format!("{} (unverified)", name).yellow(),
+                    None => "(not yet connected)".dimmed(),
+                };
+                let state = peer.presence.state();
+                let mut details = vec![state.label().to_string()];
+                if let Some(rtt) = peer.presence.rtt() {
+                    details.push(format!("rtt {} ms", rtt.as_millis()));
+                }
+                if let Some(seen) = peer.presence.last_seen() {
+                    details.push(format!("seen {}s ago", seen.as_secs()));
+                }
+                let details = details.join(", ");
+                let details = match state {
+                    PeerState::Online => details.green(),
+                    PeerState::Stale => details.yellow(),
+                    _ => details.dimmed(),
+                };
+                let others = match peer.addrs.len() {
+                    0 | 1 => "".normal(),
+                    n => format!(" (+{} more)", n - 1).dimmed(),
+                };
+                println!(
+                    "  {} - {}{} {} {}",
+                    peer.name.blue(),
+                    peer.addr,
+                    others,
+                    status,
+                    details
+                );
+            }
+            if !relayed.is_empty() {
+                println!("{}", "Reachable through relays:".green().bold());
+            }
+            for (key, name, via) in relayed {
+                let relay = peers
+                    .get(&via)
+                    .map_or_else(|| via.to_string(), |peer| peer.display_name().to_string());
+                let status = if trust.is_verified(&name, &key) {
+                    "(verified)".green()
+                } else {
+                    "(unverified)".yellow()
+                };
+                println!("  {} - via {} {}", name.blue(), relay, status);
             }
             println!();
         }
     }
 
-    async fn send_file(&self, path: &str, is_image: bool) {
-        match tokio::fs::read(path).await {
-            Ok(data) => {
-                let filename = std::path::Path::new(path)
-                    .file_name()
-                    .and_then(|n| n.to_str())
-                    .unwrap_or("unknown")
-                    .to_string();
+    /// `/verify <name>` prints the safety number to compare in person;
+    /// `/verify <name> confirm` marks the peer's current key as verified.
+    async fn verify_peer(&self, args: &str) {
+        let (name, confirm) = match args.strip_suffix(" confirm") {
+            Some(name) => (name.trim(), true),
+            None => (args, false),
+        };
+
+        let mut trust = self.trust.lock().await;
+        if confirm {
+            let Some(key) = trust.mark_verified(name) else {
+                println!(
+                    "{} {}",
+                    "Compare the safety number first with".yellow(),
+                    format!("/verify {}", name).cyan()
+                );
+                return;
+            };
+            println!("{} {}", name.blue(), "is now verified.".green());
+            if trust.current_key(name) != Some(key) {
+                println!(
+                    "{} {}",
+                    "WARNING:".red().bold(),
+                    format!("{} has presented a different key since.", name).red()
+                );
+            }
+            return;
+        }
+
+        let Some(key) = trust.show(name) else {
+            println!("{} {}", "No identity known for".yellow(), name.blue());
+            return;
+        };
 
-                let msg_type = if is_image {
-                    MessageType::Image { filename, data }
-                } else {
-                    MessageType::Video { filename, data }
-                };
+        let number = identity::safety_number(&self.identity.public_key(), &key);
+        let status = if trust.is_verified(name, &key) {
+            "verified".green()
+        } else {
+            "unverified".yellow()
+        };
+
+        println!(
+            "\n{} {} ({})",
+            "Safety number with".green().bold(),
+            name.blue(),
+            status
+        );
+        println!("  {}", number.bold());
+        println!(
+            "  {} {}",
+            "Their fingerprint:".dimmed(),
+            identity::fingerprint(&key)
+        );
+        println!(
+            "Compare it with {} in person, then run {} if it matches.\n",
+            name.blue(),
+            format!("/verify {} confirm", name).cyan()
+        );
+    }
+
+    async fn list_verified(&self) {
+        let verified = self.trust.lock().await.verified();
+        if verified.is_empty() {
+            println!("{}", "No verified peers yet.".yellow());
+        } else {
+            println!("\n{}", "Verified Peers:".green().bold());
+            for (name, fingerprint) in verified {
+                println!("  {} - {}", name.blue(), fingerprint.dimmed());
+            }
+            println!();
+        }
+    }
 
-                self.broadcast_message(msg_type).await;
+    /// Offers a file to everyone, or only to `to` when given.
+    async fn send_file(&self, path: &str, kind: FileKind, to: Option<&Peer>) {
+        let audience = match to {
+            Some(peer) => Audience {
+                recipients: peer.identity.iter().map(|(_, key)| *key).collect(),
+                public: false,
+            },
+            None => {
+                let channel = self.rooms.lock().await.current().map(str::to_string);
+                Audience {
+                    recipients: self.channel_keys(&channel).await,
+                    public: channel.is_none(),
+                }
             }
+        };
+        match self.transfers.offer(path, kind, audience).await {
+            Ok(offer) => match to {
+                Some(peer) => self.send_to_peer(peer, offer).await,
+                None => self.broadcast_message(offer).await,
+            },
             Err(e) => {
-                eprintln!("{} {}", "Error reading file:".red(), e);
+                eprintln!("{} {}", "Cannot send file:".red(), e);
             }
         }
     }
 
-    async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
+    async fn run(self: &Arc<Self>) -> Result<(), Box<dyn std::error::Error>> {
         println!("{}", "=".repeat(60).green());
         println!(
             "{}",
-            "  P2P Encrypted Chat - Secure Local Network Messaging".green().bold()
+            "  P2P Encrypted Chat - Secure Local Network Messaging"
+                .green()
+                .bold()
         );
         println!("{}", "=".repeat(60).green());
         println!();
         println!("{}  {}", "Your name:".cyan(), self.name.blue().bold());
         println!("{}      {}", "Port:".cyan(), self.port);
+        println!(
+            "{}  {}",
+            "Identity:".cyan(),
+            self.identity.fingerprint().dimmed()
+        );
         println!();
         println!("{}", "Starting services...".yellow());
 
@@ -399,6 +2652,51 @@ impl ChatApp {
         self.start_mdns_discovery().await?;
         println!("{}", "  Broadcasting presence on network".green());
 
+        if let Some(port) = self.network.beacon_port {
+            self.start_beacon(port).await?;
+            println!(
+                "{}",
+                format!("  Sending UDP beacons on port {}", port).green()
+            );
+        }
+
+        let manual = self.manual.lock().await.clone();
+        if !manual.is_empty() {
+            println!(
+                "{}",
+                format!("  Connecting to {} peer(s) added by hand", manual.len()).green()
+            );
+        }
+        for (target, _) in manual {
+            self.connect_manual(target, true);
+        }
+
+        let app = self.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(HEARTBEAT_INTERVAL).await;
+                app.check_presence().await;
+                let manual = app.manual.lock().await.clone();
+                for (target, _) in manual {
+                    app.connect_manual(target, false);
+                }
+            }
+        });
+
+        if self.mesh.lock().await.is_relaying() {
+            let app = self.clone();
+            tokio::spawn(async move {
+                loop {
+                    sleep(ROUTE_INTERVAL).await;
+                    app.announce_routes().await;
+                }
+            });
+            println!(
+                "{}",
+                "  Relaying for peers that cannot reach each other".green()
+            );
+        }
+
         tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
 
         println!();
@@ -420,7 +2718,51 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         rng.gen_range(49152..65535)
     });
 
-    let app = ChatApp::new(cli.name, port);
+    let data_dir = cli.data_dir.unwrap_or_else(identity::default_data_dir);
+    let identity = Identity::load_or_create(&data_dir)?;
+    let trust = TrustStore::load(&data_dir)?;
+    let mut manual = invite::load_peers_file(&data_dir)?;
+    manual.extend(cli.connect);
+    let outbox_ttl = Duration::from_secs(cli.outbox_ttl * 60);
+    let outbox = if cli.persist_outbox {
+        Outbox::persistent(outbox_ttl, &data_dir, &identity)?
+    } else {
+        Outbox::new(outbox_ttl)
+    };
+
+    let app = Arc::new(ChatApp::new(
+        cli.name,
+        port,
+        identity,
+        trust,
+        cli.download_dir,
+        TrafficPolicy {
+            pad: cli.pad || cli.cover_traffic.is_some(),
+            cover_interval: cli.cover_traffic.map(Duration::from_secs),
+        },
+        !cli.no_read_receipts,
+        outbox,
+        NetworkPolicy {
+            interfaces: cli.interface,
+            excluded: cli.exclude_interface,
+            ipv4_only: cli.ipv4_only,
+            beacon_port: cli.beacon,
+        },
+    ));
+    if let Some(room) = cli.room {
+        let room = rooms::parse_room(&room)?;
+        app.rooms.lock().await.join(&room, cli.room_password)?;
+    }
+    if cli.relay {
+        app.mesh.lock().await.enable_relaying();
+    }
+    *app.manual.lock().await = manual
+        .into_iter()
+        .map(|target| {
+            let fingerprint = target.fingerprint();
+            (target, fingerprint)
+        })
+        .collect();
     app.run().await?;
 
     Ok(())
@@ -0,0 +1,208 @@
+use if_addrs::{IfAddr, Interface};
+use mdns_sd::{IfKind, ServiceDaemon};
+use socket2::{Domain, Socket, Type};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use tokio::net::TcpListener;
+
+const LISTEN_BACKLOG: i32 = 128;
+/// Link-local all-nodes multicast group, which every IPv6 host is in.
+const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+
+/// Which network interfaces and address families to use, from
+/// `--interface`, `--exclude-interface` and `--ipv4-only`, and whether to
+/// look for peers with `--beacon` as well as mDNS.
+#[derive(Clone, Default)]
+pub struct NetworkPolicy {
+    /// Only these interfaces, by name; all of them if empty.
+    pub interfaces: Vec<String>,
+    pub excluded: Vec<String>,
+    pub ipv4_only: bool,
+    /// UDP port for beacons, if they are on.
+    pub beacon_port: Option<u16>,
+}
+
+impl NetworkPolicy {
+    /// Keeps mDNS to the chosen interfaces, so we are only announced, and
+    /// only look for peers, where the user wants.
+    pub fn configure(&self, mdns: &ServiceDaemon) -> mdns_sd::Result<()> {
+        if !self.interfaces.is_empty() {
+            mdns.disable_interface(IfKind::All)?;
+            mdns.enable_interface(names(&self.interfaces))?;
+        }
+        if !self.excluded.is_empty() {
+            mdns.disable_interface(names(&self.excluded))?;
+        }
+        if self.ipv4_only {
+            mdns.disable_interface(IfKind::IPv6)?;
+        }
+        Ok(())
+    }
+
+    /// Listens on every address: dual-stack IPv6, which also takes IPv4
+    /// connections, or IPv4 alone with `--ipv4-only` or where the host has
+    /// no IPv6.
+    pub fn listen(&self, port: u16) -> std::io::Result<TcpListener> {
+        if !self.ipv4_only
+            && let Ok(listener) = bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)))
+        {
+            return Ok(listener);
+        }
+        bind(SocketAddr::from(([0, 0, 0, 0], port)))
+    }
+
+    /// The addresses worth dialling out of those a peer advertises on
+    /// `port`, in the order to try them: addresses on one of our own
+    /// subnets first, alternating IPv6 and IPv4 so that a broken family
+    /// costs one attempt rather than all of them. Link-local IPv6 addresses
+    /// are only meaningful per interface, so one is tried on each of ours.
+    pub fn candidates(&self, ips: impl IntoIterator<Item = IpAddr>, port: u16) -> Vec<SocketAddr> {
+        let local = self.local_interfaces();
+        let restricted = !self.interfaces.is_empty() || !self.excluded.is_empty();
+
+        let mut on_link = Vec::new();
+        let mut routed = Vec::new();
+        for ip in ips {
+            let ip = ip.to_canonical();
+            if ip.is_unspecified() || ip.is_multicast() || (self.ipv4_only && ip.is_ipv6()) {
+                continue;
+            }
+            match ip {
+                IpAddr::V6(v6) if is_link_local(&v6) => {
+                    on_link.extend(local.iter().filter_map(|interface| match interface.addr {
+                        IfAddr::V6(ref own) if is_link_local(&own.ip) => Some(SocketAddr::V6(
+                            SocketAddrV6::new(v6, port, 0, interface.index?),
+                        )),
+                        _ => None,
+                    }));
+                }
+                _ if local.iter().any(|interface| same_subnet(interface, ip)) => {
+                    on_link.push(SocketAddr::new(ip, port));
+                }
+                // Only this host is reached over loopback, whatever the
+                // interface choice.
+                _ if !restricted || ip.is_loopback() => routed.push(SocketAddr::new(ip, port)),
+                _ => {}
+            }
+        }
+
+        let mut candidates = interleave(on_link);
+        candidates.extend(interleave(routed));
+        candidates
+    }
+
+    /// Addresses the user gave us, in the order to try them. Only
+    /// `--ipv4-only` applies, as the user chose them.
+    pub fn order(&self, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let addrs = addrs
+            .into_iter()
+            .filter(|addr| !(self.ipv4_only && addr.is_ipv6()))
+            .collect();
+        interleave(addrs)
+    }
+
+    /// Where others can reach us on `port`, for invites. Loopback and
+    /// link-local addresses mean nothing on another machine.
+    pub fn local_addrs(&self, port: u16) -> Vec<SocketAddr> {
+        let addrs = self
+            .local_interfaces()
+            .into_iter()
+            .map(|interface| interface.ip())
+            .filter(|ip| {
+                !ip.is_loopback()
+                    && match ip {
+                        IpAddr::V4(v4) => !v4.is_link_local(),
+                        IpAddr::V6(v6) => !is_link_local(v6),
+                    }
+            })
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect();
+        interleave(addrs)
+    }
+
+    /// Where to send beacons on `port`: the broadcast address of each IPv4
+    /// interface, and the all-nodes multicast group on each IPv6 one.
+    pub fn beacon_targets(&self, port: u16) -> Vec<SocketAddr> {
+        let mut targets = Vec::new();
+        for interface in self.local_interfaces() {
+            let target = match &interface.addr {
+                IfAddr::V4(v4) => v4
+                    .broadcast
+                    .map(|broadcast| SocketAddr::from((broadcast, port))),
+                IfAddr::V6(_) => interface
+                    .index
+                    .map(|index| SocketAddr::V6(SocketAddrV6::new(ALL_NODES, port, 0, index))),
+            };
+            if let Some(target) = target
+                && !interface.is_loopback()
+                && !targets.contains(&target)
+            {
+                targets.push(target);
+            }
+        }
+        targets
+    }
+
+    fn local_interfaces(&self) -> Vec<Interface> {
+        if_addrs::get_if_addrs()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|interface| {
+                (self.interfaces.is_empty() || self.interfaces.contains(&interface.name))
+                    && !self.excluded.contains(&interface.name)
+                    && !(self.ipv4_only && interface.ip().is_ipv6())
+            })
+            .collect()
+    }
+}
+
+fn names(interfaces: &[String]) -> Vec<IfKind> {
+    interfaces
+        .iter()
+        .map(|name| IfKind::Name(name.clone()))
+        .collect()
+}
+
+fn bind(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+fn is_link_local(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xffc0 == 0xfe80
+}
+
+fn same_subnet(interface: &Interface, ip: IpAddr) -> bool {
+    match (&interface.addr, ip) {
+        (IfAddr::V4(own), IpAddr::V4(ip)) => {
+            let mask = u32::from(own.netmask);
+            u32::from(own.ip) & mask == u32::from(ip) & mask
+        }
+        (IfAddr::V6(own), IpAddr::V6(ip)) => {
+            let mask = u128::from(own.netmask);
+            u128::from(own.ip) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Alternates IPv6 and IPv4 addresses, IPv6 first, keeping each family's
+/// order.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let mut v6 = v6.into_iter();
+    let mut v4 = v4.into_iter();
+    let mut ordered = Vec::new();
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
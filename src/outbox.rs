@@ -0,0 +1,252 @@
+use chacha20poly1305::{
+    ChaCha20Poly1305, Nonce,
+    aead::{Aead, KeyInit},
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::NONCE_SIZE;
+use crate::identity::{self, Identity};
+use crate::receipts::MessageId;
+
+const OUTBOX_FILE: &str = "outbox.bin";
+const OUTBOX_KEY_CONTEXT: &str = "rust-chat 2025 outbox";
+/// Per peer, so a peer that stays away cannot make the outbox grow forever.
+const MAX_QUEUED: usize = 500;
+
+type PeerKey = [u8; 32];
+
+/// A message kept for a peer that could not be reached.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Queued {
+    pub id: MessageId,
+    /// The serialized `Message`, sent over the pairwise session as is.
+    pub data: Vec<u8>,
+    /// Room it was posted in, which the peer must still share with us.
+    pub channel: Option<String>,
+    expires: i64,
+}
+
+impl Queued {
+    pub fn new(id: MessageId, data: Vec<u8>, channel: Option<String>) -> Self {
+        Self {
+            id,
+            data,
+            channel,
+            expires: 0,
+        }
+    }
+}
+
+/// A peer that has gone away, remembered so that messages sent while it is
+/// gone are queued for it too.
+#[derive(Serialize, Deserialize)]
+struct Absent {
+    name: String,
+    addr: SocketAddr,
+    rooms: BTreeSet<String>,
+    until: i64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    queues: HashMap<PeerKey, VecDeque<Queued>>,
+    absent: HashMap<PeerKey, Absent>,
+}
+
+/// Messages waiting for peers to come back, by identity key so they reach
+/// the same installation whatever address it returns on. Entries expire
+/// after the configured time. When persistent, the outbox is kept on disk
+/// encrypted under a key derived from our identity.
+pub struct Outbox {
+    state: State,
+    ttl: i64,
+    storage: Option<(PathBuf, ChaCha20Poly1305)>,
+}
+
+impl Outbox {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            state: State::default(),
+            ttl: ttl.as_secs() as i64,
+            storage: None,
+        }
+    }
+
+    /// An outbox saved under `dir`, picking up whatever was left there. A
+    /// file that cannot be read or decrypted is an error rather than an
+    /// empty outbox, as saving over it would lose every queued message.
+    pub fn persistent(ttl: Duration, dir: &Path, identity: &Identity) -> std::io::Result<Self> {
+        let path = dir.join(OUTBOX_FILE);
+        let cipher = ChaCha20Poly1305::new((&identity.derive_key(OUTBOX_KEY_CONTEXT)).into());
+
+        let state = match std::fs::read(&path) {
+            Ok(data) => decrypt_state(&cipher, &data).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Corrupt outbox at {}", path.display()),
+                )
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => State::default(),
+            Err(e) => return Err(e),
+        };
+
+        let mut outbox = Self {
+            state,
+            ttl: ttl.as_secs() as i64,
+            storage: Some((path, cipher)),
+        };
+        outbox.expire();
+        Ok(outbox)
+    }
+
+    pub fn queue(&mut self, peer: PeerKey, mut message: Queued) {
+        message.expires = now() + self.ttl;
+        let queue = self.state.queues.entry(peer).or_default();
+        if queue.iter().any(|queued| queued.id == message.id) {
+            return;
+        }
+        if queue.len() == MAX_QUEUED {
+            queue.pop_front();
+        }
+        queue.push_back(message);
+        self.save();
+    }
+
+    /// Remembers a peer that left the network along with the rooms it was in.
+    pub fn peer_left(
+        &mut self,
+        peer: PeerKey,
+        name: String,
+        addr: SocketAddr,
+        rooms: BTreeSet<String>,
+    ) {
+        let until = now() + self.ttl;
+        self.state.absent.insert(
+            peer,
+            Absent {
+                name,
+                addr,
+                rooms,
+                until,
+            },
+        );
+        self.save();
+    }
+
+    /// Absent peers that would have received a message to `channel`, with
+    /// the address and name they were last seen under.
+    pub fn absent_members(
+        &mut self,
+        channel: &Option<String>,
+    ) -> Vec<(PeerKey, SocketAddr, String)> {
+        self.expire();
+        self.state
+            .absent
+            .iter()
+            .filter(|(_, absent)| match channel {
+                Some(room) => absent.rooms.contains(room),
+                None => true,
+            })
+            .map(|(key, absent)| (*key, absent.addr, absent.name.clone()))
+            .collect()
+    }
+
+    /// Marks `peer` as back and hands over everything queued for it.
+    pub fn peer_returned(&mut self, peer: &PeerKey) -> Vec<Queued> {
+        self.expire();
+        let absent = self.state.absent.remove(peer).is_some();
+        let queued: Vec<Queued> = self
+            .state
+            .queues
+            .remove(peer)
+            .map(Vec::from)
+            .unwrap_or_default();
+        if absent || !queued.is_empty() {
+            self.save();
+        }
+        queued
+    }
+
+    /// Puts back messages that still could not be sent, ahead of anything
+    /// queued since.
+    pub fn requeue(&mut self, peer: PeerKey, messages: Vec<Queued>) {
+        if messages.is_empty() {
+            return;
+        }
+        let queue = self.state.queues.entry(peer).or_default();
+        for message in messages.into_iter().rev() {
+            queue.push_front(message);
+        }
+        queue.truncate(MAX_QUEUED);
+        self.save();
+    }
+
+    fn expire(&mut self) {
+        let now = now();
+        let before = self.len();
+        let absent = self.state.absent.len();
+        self.state.absent.retain(|_, absent| absent.until > now);
+        for queue in self.state.queues.values_mut() {
+            queue.retain(|message| message.expires > now);
+        }
+        self.state.queues.retain(|_, queue| !queue.is_empty());
+        if self.len() != before || self.state.absent.len() != absent {
+            self.save();
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.state.queues.values().map(VecDeque::len).sum()
+    }
+
+    fn save(&self) {
+        let Some((path, cipher)) = &self.storage else {
+            return;
+        };
+        if let Err(e) = save_state(path, cipher, &self.state) {
+            eprintln!("Failed to save outbox: {}", e);
+        }
+    }
+}
+
+fn save_state(
+    path: &Path,
+    cipher: &ChaCha20Poly1305,
+    state: &State,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut nonce_bytes = [0u8; NONCE_SIZE];
+    rand::thread_rng().fill(&mut nonce_bytes);
+    let ciphertext = cipher
+        .encrypt(
+            Nonce::from_slice(&nonce_bytes),
+            bincode::serialize(state)?.as_slice(),
+        )
+        .map_err(|e| format!("Encryption error: {}", e))?;
+
+    let mut data = nonce_bytes.to_vec();
+    data.extend_from_slice(&ciphertext);
+    if let Some(dir) = path.parent() {
+        identity::create_private_dir(dir)?;
+    }
+    identity::write_private_file(path, &data)?;
+    Ok(())
+}
+
+fn decrypt_state(cipher: &ChaCha20Poly1305, data: &[u8]) -> Option<State> {
+    if data.len() < NONCE_SIZE {
+        return None;
+    }
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(&data[..NONCE_SIZE]), &data[NONCE_SIZE..])
+        .ok()?;
+    bincode::deserialize(&plaintext).ok()
+}
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
@@ -0,0 +1,204 @@
+use chacha20poly1305::{
+    ChaCha20Poly1305, Nonce,
+    aead::{Aead, KeyInit, Payload},
+};
+use rand::Rng;
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use crate::NONCE_SIZE;
+
+const ROOM_KEY_CONTEXT: &str = "rust-chat 2025 room key";
+const CONFIRM_KEY_CONTEXT: &str = "rust-chat 2025 room key confirmation";
+
+type RoomPeer = (SocketAddr, String);
+
+struct RoomKey {
+    cipher: ChaCha20Poly1305,
+    confirm: [u8; 32],
+}
+
+/// Pairwise keys for password-protected rooms.
+///
+/// Each pair of members runs symmetric SPAKE2 over their encrypted session,
+/// so the password itself is never sent and a wrong guess costs an online
+/// attempt against a live peer. A key is only used once the other side has
+/// proven it derived the same one.
+#[derive(Default)]
+pub struct RoomKeys {
+    pending: HashMap<RoomPeer, Spake2<Ed25519Group>>,
+    unconfirmed: HashMap<RoomPeer, RoomKey>,
+    confirmed: HashMap<RoomPeer, RoomKey>,
+}
+
+impl RoomKeys {
+    /// Starts an exchange with the peer at `addr` unless one is already
+    /// running or done. Returns the SPAKE2 message to send.
+    pub fn start(&mut self, addr: SocketAddr, room: &str, password: &str) -> Option<Vec<u8>> {
+        let id = (addr, room.to_string());
+        if self.pending.contains_key(&id)
+            || self.unconfirmed.contains_key(&id)
+            || self.confirmed.contains_key(&id)
+        {
+            return None;
+        }
+
+        let (state, message) = start_spake2(room, password);
+        self.pending.insert(id, state);
+        Some(message)
+    }
+
+    /// Completes an exchange with the peer's SPAKE2 message, answering it
+    /// first if the peer started. Returns our reply, if any, and the
+    /// confirmation tag to send.
+    pub fn receive(
+        &mut self,
+        addr: SocketAddr,
+        room: &str,
+        password: &str,
+        message: &[u8],
+        our_key: &[u8; 32],
+        peer_key: &[u8; 32],
+    ) -> Result<(Option<Vec<u8>>, [u8; 32]), String> {
+        let id = (addr, room.to_string());
+        self.unconfirmed.remove(&id);
+        self.confirmed.remove(&id);
+
+        let (state, reply) = match self.pending.remove(&id) {
+            Some(state) => (state, None),
+            None => {
+                let (state, reply) = start_spake2(room, password);
+                (state, Some(reply))
+            }
+        };
+
+        let shared = state
+            .finish(message)
+            .map_err(|e| format!("Room key exchange failed: {:?}", e))?;
+
+        let (first, second) = if our_key <= peer_key {
+            (our_key, peer_key)
+        } else {
+            (peer_key, our_key)
+        };
+        let mut material = shared;
+        material.extend_from_slice(first);
+        material.extend_from_slice(second);
+        material.extend_from_slice(room.as_bytes());
+
+        let key = RoomKey {
+            cipher: ChaCha20Poly1305::new(
+                (&blake3::derive_key(ROOM_KEY_CONTEXT, &material)).into(),
+            ),
+            confirm: blake3::derive_key(CONFIRM_KEY_CONTEXT, &material),
+        };
+        let tag = confirmation_tag(&key.confirm, our_key, room);
+        self.unconfirmed.insert(id, key);
+
+        Ok((reply, tag))
+    }
+
+    /// Checks the peer's confirmation tag. On success the key is used for
+    /// the room from now on; on failure the peer used a different password.
+    pub fn confirm(
+        &mut self,
+        addr: SocketAddr,
+        room: &str,
+        tag: &[u8],
+        peer_key: &[u8; 32],
+    ) -> Result<(), String> {
+        let id = (addr, room.to_string());
+        let key = self
+            .unconfirmed
+            .remove(&id)
+            .ok_or("Unexpected room key confirmation")?;
+
+        // blake3::Hash compares in constant time.
+        let expected = blake3::Hash::from(confirmation_tag(&key.confirm, peer_key, room));
+        let tag: [u8; 32] = tag.try_into().map_err(|_| "Invalid confirmation tag")?;
+        if expected != blake3::Hash::from(tag) {
+            return Err("Room password does not match".to_string());
+        }
+        self.confirmed.insert(id, key);
+        Ok(())
+    }
+
+    pub fn is_confirmed(&self, addr: SocketAddr, room: &str) -> bool {
+        self.confirmed.contains_key(&(addr, room.to_string()))
+    }
+
+    /// Encrypts a message for one member, bound to the room name.
+    pub fn seal(&self, addr: SocketAddr, room: &str, data: &[u8]) -> Result<Vec<u8>, String> {
+        let key = self
+            .confirmed
+            .get(&(addr, room.to_string()))
+            .ok_or("Room password not confirmed yet")?;
+
+        let mut nonce_bytes = [0u8; NONCE_SIZE];
+        rand::thread_rng().fill(&mut nonce_bytes);
+        let ciphertext = key
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&nonce_bytes),
+                Payload {
+                    msg: data,
+                    aad: room.as_bytes(),
+                },
+            )
+            .map_err(|e| format!("Encryption error: {}", e))?;
+
+        let mut result = nonce_bytes.to_vec();
+        result.extend_from_slice(&ciphertext);
+        Ok(result)
+    }
+
+    pub fn open(&self, addr: SocketAddr, room: &str, data: &[u8]) -> Result<Vec<u8>, String> {
+        let key = self
+            .confirmed
+            .get(&(addr, room.to_string()))
+            .ok_or("Room password not confirmed")?;
+        if data.len() < NONCE_SIZE {
+            return Err("Frame too short".to_string());
+        }
+
+        key.cipher
+            .decrypt(
+                Nonce::from_slice(&data[..NONCE_SIZE]),
+                Payload {
+                    msg: &data[NONCE_SIZE..],
+                    aad: room.as_bytes(),
+                },
+            )
+            .map_err(|e| format!("Decryption error: {}", e))
+    }
+
+    /// Drops every key shared with `addr`, e.g. once its session closes.
+    pub fn forget_peer(&mut self, addr: SocketAddr) {
+        self.pending.retain(|(a, _), _| *a != addr);
+        self.unconfirmed.retain(|(a, _), _| *a != addr);
+        self.confirmed.retain(|(a, _), _| *a != addr);
+    }
+
+    /// Drops every key for `room`, e.g. after leaving it or changing password.
+    pub fn forget_room(&mut self, room: &str) {
+        self.pending.retain(|(_, r), _| r != room);
+        self.unconfirmed.retain(|(_, r), _| r != room);
+        self.confirmed.retain(|(_, r), _| r != room);
+    }
+}
+
+fn start_spake2(room: &str, password: &str) -> (Spake2<Ed25519Group>, Vec<u8>) {
+    Spake2::<Ed25519Group>::start_symmetric(
+        &Password::new(password.as_bytes()),
+        &Identity::new(room.as_bytes()),
+    )
+}
+
+/// Tag proving knowledge of the room key, bound to the sender's identity so
+/// a peer cannot simply echo ours back.
+fn confirmation_tag(confirm_key: &[u8; 32], sender_key: &[u8; 32], room: &str) -> [u8; 32] {
+    let mut data = sender_key.to_vec();
+    data.extend_from_slice(room.as_bytes());
+    *blake3::keyed_hash(confirm_key, &data).as_bytes()
+}
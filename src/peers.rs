@@ -0,0 +1,191 @@
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+
+use crate::identity;
+use crate::presence::Presence;
+
+type PeerKey = [u8; 32];
+
+#[derive(Clone)]
+pub struct Peer {
+    /// Current mDNS instance name, a random id until the handshake.
+    pub name: String,
+    /// The id advertised with the instance name, see
+    /// [`identity::instance_tag`].
+    pub tag: Option<String>,
+    /// The address our session with the peer runs over, which sessions,
+    /// room keys and receipts know it by.
+    pub addr: SocketAddr,
+    /// Every address the peer is known at, best first.
+    pub addrs: Vec<SocketAddr>,
+    /// Display name and identity key, known once a handshake has completed.
+    pub identity: Option<(String, PeerKey)>,
+    /// Rooms the peer told us it is in over its session.
+    pub rooms: BTreeSet<String>,
+    pub presence: Presence,
+}
+
+impl Peer {
+    fn new(name: String, addrs: Vec<SocketAddr>) -> Self {
+        Self {
+            name,
+            tag: None,
+            addr: addrs[0],
+            addrs,
+            identity: None,
+            rooms: BTreeSet::new(),
+            presence: Presence::default(),
+        }
+    }
+
+    /// The name from the handshake once known, else the instance name.
+    pub fn display_name(&self) -> &str {
+        match &self.identity {
+            Some((name, _)) => name,
+            None => &self.name,
+        }
+    }
+
+    /// Whether the instance this peer was found under is advertised by the
+    /// owner of `key`. Builds that advertise no tag are matched by address.
+    fn advertised_by(&self, key: &PeerKey, addr: SocketAddr) -> bool {
+        match &self.tag {
+            Some(tag) => *tag == identity::instance_tag(key, &self.name),
+            None => self.addrs.contains(&addr),
+        }
+    }
+
+    /// Takes the addresses mDNS now lists for the peer.
+    fn readdress(&mut self, addrs: Vec<SocketAddr>) {
+        self.addrs = addrs;
+        // A session keeps its address even once it is no longer
+        // advertised, for as long as the session lasts.
+        if !self.addrs.contains(&self.addr) {
+            if self.identity.is_some() {
+                self.addrs.push(self.addr);
+            } else {
+                self.addr = self.addrs[0];
+            }
+        }
+    }
+}
+
+/// Everyone we know of: peers we have had a handshake with by identity key,
+/// so one that changes address, port or instance name stays one entry, and
+/// peers mDNS found that we have yet to hear from by instance name.
+#[derive(Default)]
+pub struct Peers {
+    known: HashMap<PeerKey, Peer>,
+    discovered: HashMap<String, Peer>,
+}
+
+impl Peers {
+    /// The peer whose session runs over `addr`.
+    pub fn get(&self, addr: &SocketAddr) -> Option<&Peer> {
+        self.values().find(|peer| peer.addr == *addr)
+    }
+
+    pub fn get_mut(&mut self, addr: &SocketAddr) -> Option<&mut Peer> {
+        self.values_mut().find(|peer| peer.addr == *addr)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Peer> {
+        self.known.values().chain(self.discovered.values())
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut Peer> {
+        self.known.values_mut().chain(self.discovered.values_mut())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.known.is_empty() && self.discovered.is_empty()
+    }
+
+    /// Records that mDNS resolved `instance`, tagged `tag`, at `addrs`. If
+    /// the tag shows it is a peer we know, that peer takes the new name and
+    /// addresses.
+    pub fn discovered(
+        &mut self,
+        instance: &str,
+        tag: Option<String>,
+        addrs: Vec<SocketAddr>,
+    ) -> &mut Peer {
+        let known = self
+            .known
+            .iter()
+            .find(|(key, peer)| match &tag {
+                Some(tag) => *tag == identity::instance_tag(key, instance),
+                None => peer.addrs.iter().any(|addr| addrs.contains(addr)),
+            })
+            .map(|(key, _)| *key);
+
+        let peer = match known.and_then(|key| self.known.get_mut(&key)) {
+            Some(peer) => peer,
+            None => self
+                .discovered
+                .entry(instance.to_string())
+                .or_insert_with(|| Peer::new(instance.to_string(), addrs.clone())),
+        };
+        peer.name = instance.to_string();
+        peer.tag = tag;
+        peer.readdress(addrs);
+        peer
+    }
+
+    /// Records a finished handshake with the owner of `key` over `addr`.
+    /// Instances found by mDNS whose tag the key confirms are folded into
+    /// its entry. The caller points the entry at `addr`.
+    pub fn connected(&mut self, addr: SocketAddr, name: &str, key: PeerKey) -> &mut Peer {
+        let mut found: Vec<Peer> = self
+            .discovered
+            .extract_if(|_, peer| peer.advertised_by(&key, addr))
+            .map(|(_, peer)| peer)
+            .collect();
+
+        let peer = self.known.entry(key).or_insert_with(|| {
+            found
+                .pop()
+                .unwrap_or_else(|| Peer::new(name.to_string(), vec![addr]))
+        });
+        for other in found {
+            for other_addr in other.addrs {
+                if !peer.addrs.contains(&other_addr) {
+                    peer.addrs.push(other_addr);
+                }
+            }
+        }
+        if !peer.addrs.contains(&addr) {
+            peer.addrs.push(addr);
+        }
+        peer
+    }
+
+    /// The peer mDNS announced as `instance`, if it is still known by that
+    /// name.
+    pub fn instance(&self, instance: &str) -> Option<&Peer> {
+        self.values().find(|peer| peer.name == instance)
+    }
+
+    /// Drops the peer mDNS announced as `instance`, if it is still known by
+    /// that name.
+    pub fn remove_instance(&mut self, instance: &str) -> Option<Peer> {
+        if let Some(peer) = self.discovered.remove(instance) {
+            return Some(peer);
+        }
+        let key = self
+            .known
+            .iter()
+            .find(|(_, peer)| peer.name == instance)
+            .map(|(key, _)| *key)?;
+        self.known.remove(&key)
+    }
+
+    /// Drops every peer `gone` returns true for.
+    pub fn remove_where(&mut self, gone: impl Fn(&Peer) -> bool) -> Vec<Peer> {
+        let known = self.known.extract_if(|_, peer| gone(peer));
+        let mut removed: Vec<Peer> = known.map(|(_, peer)| peer).collect();
+        let discovered = self.discovered.extract_if(|_, peer| gone(peer));
+        removed.extend(discovered.map(|(_, peer)| peer));
+        removed
+    }
+}
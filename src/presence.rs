@@ -0,0 +1,136 @@
+use rand::Rng;
+use std::time::{Duration, Instant};
+
+/// How often connected peers are sent a heartbeat.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+/// Silence after which a peer is shown as stale.
+const STALE_AFTER: Duration = Duration::from_secs(40);
+/// Silence, or time spent failing to connect, after which a peer is dropped.
+const GONE_AFTER: Duration = Duration::from_secs(120);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerState {
+    /// Seen on mDNS, not yet dialled.
+    Discovered,
+    /// Dialled, handshake not finished.
+    Connecting,
+    /// Heard from recently.
+    Online,
+    /// Connected before, but silent or disconnected for a while.
+    Stale,
+    /// Silent for so long that the peer is dropped.
+    Gone,
+}
+
+impl PeerState {
+    pub fn label(self) -> &'static str {
+        match self {
+            PeerState::Discovered => "discovered",
+            PeerState::Connecting => "connecting",
+            PeerState::Online => "online",
+            PeerState::Stale => "stale",
+            PeerState::Gone => "gone",
+        }
+    }
+}
+
+/// Where a peer stands, from mDNS discovery to heartbeats going unanswered.
+#[derive(Clone)]
+pub struct Presence {
+    state: PeerState,
+    /// When the state last changed.
+    since: Instant,
+    last_seen: Option<Instant>,
+    rtt: Option<Duration>,
+    /// The heartbeat awaiting an answer and when it was sent.
+    ping: Option<(u64, Instant)>,
+}
+
+impl Default for Presence {
+    fn default() -> Self {
+        Self {
+            state: PeerState::Discovered,
+            since: Instant::now(),
+            last_seen: None,
+            rtt: None,
+            ping: None,
+        }
+    }
+}
+
+impl Presence {
+    pub fn state(&self) -> PeerState {
+        self.state
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Time since anything last arrived from the peer.
+    pub fn last_seen(&self) -> Option<Duration> {
+        self.last_seen.map(|seen| seen.elapsed())
+    }
+
+    /// We are dialling the peer; ignored once it has been online.
+    pub fn connecting(&mut self) {
+        if self.state == PeerState::Discovered {
+            self.set(PeerState::Connecting);
+        }
+    }
+
+    /// Something arrived from the peer.
+    pub fn heard(&mut self) {
+        self.last_seen = Some(Instant::now());
+        if self.state != PeerState::Online {
+            self.set(PeerState::Online);
+        }
+    }
+
+    /// The session closed; the next heartbeat will try to redial.
+    pub fn disconnected(&mut self) {
+        if self.state == PeerState::Online {
+            self.set(PeerState::Stale);
+        }
+    }
+
+    /// Starts a heartbeat, returning the nonce to send.
+    pub fn ping(&mut self) -> u64 {
+        let nonce = rand::thread_rng().r#gen();
+        self.ping = Some((nonce, Instant::now()));
+        nonce
+    }
+
+    /// Records the answer to a heartbeat.
+    pub fn pong(&mut self, nonce: u64) {
+        if let Some((sent, at)) = self.ping
+            && sent == nonce
+        {
+            self.rtt = Some(at.elapsed());
+            self.ping = None;
+        }
+    }
+
+    /// Moves the peer along as time passes without word from it, and
+    /// returns where it now stands.
+    pub fn tick(&mut self) -> PeerState {
+        let silent = self
+            .last_seen
+            .map_or(self.since.elapsed(), |seen| seen.elapsed());
+        match self.state {
+            PeerState::Online if silent >= STALE_AFTER => self.set(PeerState::Stale),
+            PeerState::Discovered | PeerState::Connecting | PeerState::Stale
+                if silent >= GONE_AFTER =>
+            {
+                self.set(PeerState::Gone)
+            }
+            _ => {}
+        }
+        self.state
+    }
+
+    fn set(&mut self, state: PeerState) {
+        self.state = state;
+        self.since = Instant::now();
+    }
+}
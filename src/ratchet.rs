@@ -0,0 +1,452 @@
+use chacha20poly1305::{
+    ChaCha20Poly1305, Nonce,
+    aead::{Aead, KeyInit, Payload},
+};
+use std::collections::{HashMap, VecDeque};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const ROOT_KDF_CONTEXT: &str = "rust-chat 2025 ratchet root step";
+const HEADER_SIZE: usize = 32 + 4 + 4;
+/// How many message keys may be skipped in one chain, so a malicious header
+/// cannot make us derive keys forever.
+const MAX_SKIP: u32 = 1000;
+/// How many skipped message keys are kept across all chains, so a peer
+/// that keeps switching ratchet keys cannot make the state grow forever.
+const MAX_SKIPPED: usize = 2 * MAX_SKIP as usize;
+
+type SkippedId = ([u8; 32], u32);
+
+/// Signal-style double ratchet for one session.
+///
+/// Every message is encrypted under its own key from a hash chain that is
+/// stepped forward and forgotten, so a stolen state cannot decrypt earlier
+/// traffic. Whenever the direction of conversation changes the two sides
+/// also mix in a fresh X25519 exchange, so the session heals once an
+/// attacker loses access to the current state.
+#[derive(Clone)]
+pub struct Ratchet {
+    root: [u8; 32],
+    our_secret: StaticSecret,
+    our_public: PublicKey,
+    their_public: PublicKey,
+    send_chain: Option<[u8; 32]>,
+    recv_chain: Option<[u8; 32]>,
+    send_count: u32,
+    recv_count: u32,
+    prev_send_count: u32,
+    skipped: SkippedKeys,
+}
+
+/// Keys for messages that were skipped over, by ratchet key and index,
+/// dropping the oldest beyond [`MAX_SKIPPED`].
+#[derive(Clone, Default)]
+#[cfg_attr(test, derive(PartialEq, Debug))]
+struct SkippedKeys {
+    keys: HashMap<SkippedId, [u8; 32]>,
+    order: VecDeque<SkippedId>,
+}
+
+impl SkippedKeys {
+    fn get(&self, id: &SkippedId) -> Option<&[u8; 32]> {
+        self.keys.get(id)
+    }
+
+    fn remove(&mut self, id: &SkippedId) {
+        if self.keys.remove(id).is_some() {
+            self.order.retain(|skipped| skipped != id);
+        }
+    }
+
+    fn insert(&mut self, id: SkippedId, key: [u8; 32]) {
+        self.keys.insert(id, key);
+        self.order.push_back(id);
+        while self.keys.len() > MAX_SKIPPED {
+            if let Some(oldest) = self.order.pop_front() {
+                self.keys.remove(&oldest);
+            }
+        }
+    }
+
+    /// Adds the keys `newer` collected, in the order they were skipped.
+    fn append(&mut self, mut newer: SkippedKeys) {
+        for id in newer.order {
+            if let Some(key) = newer.keys.remove(&id) {
+                self.insert(id, key);
+            }
+        }
+    }
+}
+
+struct Header {
+    public: PublicKey,
+    prev_count: u32,
+    count: u32,
+}
+
+impl Header {
+    fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        bytes[..32].copy_from_slice(self.public.as_bytes());
+        bytes[32..36].copy_from_slice(&self.prev_count.to_be_bytes());
+        bytes[36..].copy_from_slice(&self.count.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let public: [u8; 32] = bytes.get(..32)?.try_into().ok()?;
+        Some(Self {
+            public: PublicKey::from(public),
+            prev_count: u32::from_be_bytes(bytes.get(32..36)?.try_into().ok()?),
+            count: u32::from_be_bytes(bytes.get(36..HEADER_SIZE)?.try_into().ok()?),
+        })
+    }
+}
+
+impl Ratchet {
+    /// Fresh ratchet key pair; the public half is exchanged during the handshake.
+    pub fn keypair() -> (StaticSecret, PublicKey) {
+        let secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+        let public = PublicKey::from(&secret);
+        (secret, public)
+    }
+
+    /// State for the side that opened the connection, which sends first
+    /// under a chain derived from both initial ratchet keys.
+    pub fn initiator(root: [u8; 32], our_secret: StaticSecret, their_public: PublicKey) -> Self {
+        let (root, send_chain) = root_step(&root, &our_secret, &their_public);
+        Self {
+            root,
+            our_public: PublicKey::from(&our_secret),
+            our_secret,
+            their_public,
+            send_chain: Some(send_chain),
+            recv_chain: None,
+            send_count: 0,
+            recv_count: 0,
+            prev_send_count: 0,
+            skipped: SkippedKeys::default(),
+        }
+    }
+
+    /// State for the side that accepted the connection. It takes the
+    /// initiator's key as if a first message had arrived, then steps its
+    /// own key so it can send straight away too.
+    pub fn responder(root: [u8; 32], our_secret: StaticSecret, their_public: PublicKey) -> Self {
+        let (root, recv_chain) = root_step(&root, &our_secret, &their_public);
+        let mut ratchet = Self {
+            root,
+            our_public: PublicKey::from(&our_secret),
+            our_secret,
+            their_public,
+            send_chain: None,
+            recv_chain: Some(recv_chain),
+            send_count: 0,
+            recv_count: 0,
+            prev_send_count: 0,
+            skipped: SkippedKeys::default(),
+        };
+        ratchet.step_sending();
+        ratchet
+    }
+
+    /// Encrypts with the next message key, prefixing the ratchet header.
+    /// `associated` is authenticated along with the header but not sent.
+    pub fn encrypt(&mut self, data: &[u8], associated: &[u8]) -> Result<Vec<u8>, String> {
+        let chain = self
+            .send_chain
+            .as_mut()
+            .ok_or("Ratchet has no sending chain")?;
+        let message_key = chain_step(chain);
+
+        let header = Header {
+            public: self.our_public,
+            prev_count: self.prev_send_count,
+            count: self.send_count,
+        }
+        .to_bytes();
+        self.send_count += 1;
+
+        let ciphertext = seal(&message_key, &[&header[..], associated].concat(), data)?;
+        let mut result = header.to_vec();
+        result.extend_from_slice(&ciphertext);
+        Ok(result)
+    }
+
+    /// Decrypts a message, stepping the ratchet as needed. State only
+    /// changes if the message authenticates, so garbage cannot desync it.
+    pub fn decrypt(&mut self, data: &[u8], associated: &[u8]) -> Result<Vec<u8>, String> {
+        let header = Header::from_bytes(data).ok_or("Frame too short")?;
+        let (header_bytes, ciphertext) = data.split_at(HEADER_SIZE);
+        let aad = [header_bytes, associated].concat();
+
+        let id = (*header.public.as_bytes(), header.count);
+        if let Some(message_key) = self.skipped.get(&id) {
+            let plaintext = open(message_key, &aad, ciphertext)?;
+            self.skipped.remove(&id);
+            return Ok(plaintext);
+        }
+
+        // Work on a copy so a frame that fails to authenticate changes
+        // nothing. The copy only collects the keys this frame skips.
+        let mut next = self.fork();
+        if header.public != next.their_public {
+            next.skip_to(header.prev_count)?;
+            next.their_public = header.public;
+            let (root, recv_chain) = root_step(&next.root, &next.our_secret, &next.their_public);
+            next.root = root;
+            next.recv_chain = Some(recv_chain);
+            next.recv_count = 0;
+            next.step_sending();
+        }
+        next.skip_to(header.count)?;
+
+        let chain = next
+            .recv_chain
+            .as_mut()
+            .ok_or("Ratchet has no receiving chain")?;
+        let message_key = chain_step(chain);
+        next.recv_count += 1;
+
+        let plaintext = open(&message_key, &aad, ciphertext)?;
+        let mut skipped = std::mem::take(&mut self.skipped);
+        skipped.append(std::mem::take(&mut next.skipped));
+        next.skipped = skipped;
+        *self = next;
+        Ok(plaintext)
+    }
+
+    /// A copy of the chains without the skipped keys, which can be many.
+    fn fork(&self) -> Self {
+        Self {
+            our_secret: self.our_secret.clone(),
+            skipped: SkippedKeys::default(),
+            ..*self
+        }
+    }
+
+    /// Replaces our ratchet key and starts a new sending chain from it.
+    fn step_sending(&mut self) {
+        let (secret, public) = Self::keypair();
+        let (root, send_chain) = root_step(&self.root, &secret, &self.their_public);
+        self.our_secret = secret;
+        self.our_public = public;
+        self.root = root;
+        self.send_chain = Some(send_chain);
+        self.prev_send_count = self.send_count;
+        self.send_count = 0;
+    }
+
+    /// Stores keys for any messages in the current receiving chain before `until`.
+    fn skip_to(&mut self, until: u32) -> Result<(), String> {
+        let Some(chain) = self.recv_chain.as_mut() else {
+            return Ok(());
+        };
+        if until.saturating_sub(self.recv_count) > MAX_SKIP {
+            return Err("Too many skipped messages".to_string());
+        }
+        while self.recv_count < until {
+            let message_key = chain_step(chain);
+            self.skipped.insert(
+                (*self.their_public.as_bytes(), self.recv_count),
+                message_key,
+            );
+            self.recv_count += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Mixes a DH output into the root key, yielding a new root and chain key.
+fn root_step(root: &[u8; 32], secret: &StaticSecret, public: &PublicKey) -> ([u8; 32], [u8; 32]) {
+    let shared = secret.diffie_hellman(public);
+    let mut hasher = blake3::Hasher::new_derive_key(ROOT_KDF_CONTEXT);
+    hasher.update(root);
+    hasher.update(shared.as_bytes());
+
+    let mut output = [0u8; 64];
+    hasher.finalize_xof().fill(&mut output);
+    let (new_root, chain) = output.split_at(32);
+    (new_root.try_into().unwrap(), chain.try_into().unwrap())
+}
+
+/// Advances a chain key and returns the message key for this step.
+pub fn chain_step(chain: &mut [u8; 32]) -> [u8; 32] {
+    let message_key = *blake3::keyed_hash(chain, &[1]).as_bytes();
+    *chain = *blake3::keyed_hash(chain, &[2]).as_bytes();
+    message_key
+}
+
+// Each message key encrypts exactly one message, so a fixed nonce is safe.
+fn seal(key: &[u8; 32], aad: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+    ChaCha20Poly1305::new(key.into())
+        .encrypt(Nonce::from_slice(&[0u8; 12]), Payload { msg: data, aad })
+        .map_err(|e| format!("Encryption error: {}", e))
+}
+
+fn open(key: &[u8; 32], aad: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+    ChaCha20Poly1305::new(key.into())
+        .decrypt(Nonce::from_slice(&[0u8; 12]), Payload { msg: data, aad })
+        .map_err(|e| format!("Decryption error: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AD: &[u8] = b"frame header";
+
+    fn pair() -> (Ratchet, Ratchet) {
+        let root = rand::random();
+        let (alice_secret, alice_public) = Ratchet::keypair();
+        let (bob_secret, bob_public) = Ratchet::keypair();
+        (
+            Ratchet::initiator(root, alice_secret, bob_public),
+            Ratchet::responder(root, bob_secret, alice_public),
+        )
+    }
+
+    fn send(ratchet: &mut Ratchet, text: &str) -> Vec<u8> {
+        ratchet.encrypt(text.as_bytes(), AD).unwrap()
+    }
+
+    fn receive(ratchet: &mut Ratchet, data: &[u8]) -> Result<String, String> {
+        ratchet
+            .decrypt(data, AD)
+            .map(|plaintext| String::from_utf8(plaintext).unwrap())
+    }
+
+    #[test]
+    fn round_trip() {
+        let (mut alice, mut bob) = pair();
+        let data = send(&mut alice, "hello");
+        assert_eq!(receive(&mut bob, &data).unwrap(), "hello");
+        // Each message key is used once.
+        assert!(receive(&mut bob, &data).is_err());
+    }
+
+    #[test]
+    fn associated_data_is_authenticated() {
+        let (mut alice, mut bob) = pair();
+        let data = send(&mut alice, "hello");
+        assert!(bob.decrypt(&data, b"another header").is_err());
+        assert_eq!(receive(&mut bob, &data).unwrap(), "hello");
+    }
+
+    #[test]
+    fn out_of_order_messages_use_skipped_keys() {
+        let (mut alice, mut bob) = pair();
+        let first = send(&mut alice, "one");
+        let second = send(&mut alice, "two");
+        let third = send(&mut alice, "three");
+
+        assert_eq!(receive(&mut bob, &third).unwrap(), "three");
+        assert_eq!(bob.skipped.keys.len(), 2);
+        assert_eq!(receive(&mut bob, &first).unwrap(), "one");
+        assert_eq!(receive(&mut bob, &second).unwrap(), "two");
+        assert!(bob.skipped.keys.is_empty());
+        assert!(bob.skipped.order.is_empty());
+        assert!(receive(&mut bob, &first).is_err());
+    }
+
+    #[test]
+    fn skipping_is_capped() {
+        let (mut alice, mut bob) = pair();
+        let messages: Vec<_> = (0..=MAX_SKIP + 1)
+            .map(|i| send(&mut alice, &i.to_string()))
+            .collect();
+
+        let too_far = &messages[MAX_SKIP as usize + 1];
+        assert!(receive(&mut bob, too_far).is_err());
+        assert!(bob.skipped.keys.is_empty());
+
+        let furthest = &messages[MAX_SKIP as usize];
+        assert_eq!(receive(&mut bob, furthest).unwrap(), MAX_SKIP.to_string());
+        assert_eq!(bob.skipped.keys.len(), MAX_SKIP as usize);
+    }
+
+    #[test]
+    fn stored_skipped_keys_are_capped_oldest_first() {
+        let (mut alice, mut bob) = pair();
+        let mut late = Vec::new();
+        for round in 0..3 {
+            let messages: Vec<_> = (0..=MAX_SKIP)
+                .map(|i| send(&mut alice, &format!("{} {}", round, i)))
+                .collect();
+            late.push(messages[0].clone());
+            assert!(receive(&mut bob, messages.last().unwrap()).is_ok());
+
+            // A reply makes Alice's next chain start from a new ratchet key.
+            let reply = send(&mut bob, "reply");
+            assert_eq!(receive(&mut alice, &reply).unwrap(), "reply");
+        }
+
+        assert_eq!(bob.skipped.keys.len(), MAX_SKIPPED);
+        assert_eq!(bob.skipped.order.len(), MAX_SKIPPED);
+        assert!(receive(&mut bob, &late[0]).is_err());
+        assert_eq!(receive(&mut bob, &late[1]).unwrap(), "1 0");
+        assert_eq!(receive(&mut bob, &late[2]).unwrap(), "2 0");
+    }
+
+    #[test]
+    fn replies_step_the_ratchet() {
+        let (mut alice, mut bob) = pair();
+        let alice_key = alice.our_public;
+        let bob_key = bob.our_public;
+
+        for round in 0..3 {
+            let data = send(&mut alice, &format!("ping {}", round));
+            assert_eq!(receive(&mut bob, &data).unwrap(), format!("ping {}", round));
+            let data = send(&mut bob, &format!("pong {}", round));
+            assert_eq!(
+                receive(&mut alice, &data).unwrap(),
+                format!("pong {}", round)
+            );
+        }
+
+        assert_ne!(alice.our_public, alice_key);
+        assert_ne!(bob.our_public, bob_key);
+        assert_eq!(alice.their_public, bob.our_public);
+    }
+
+    #[test]
+    fn late_messages_from_an_earlier_chain_still_decrypt() {
+        let (mut alice, mut bob) = pair();
+        let first = send(&mut alice, "one");
+        let late = send(&mut alice, "two");
+        assert_eq!(receive(&mut bob, &first).unwrap(), "one");
+
+        let reply = send(&mut bob, "reply");
+        assert_eq!(receive(&mut alice, &reply).unwrap(), "reply");
+        let after = send(&mut alice, "three");
+
+        // The new ratchet key arrives before the rest of the old chain.
+        assert_eq!(receive(&mut bob, &after).unwrap(), "three");
+        assert_eq!(receive(&mut bob, &late).unwrap(), "two");
+    }
+
+    #[test]
+    fn tampered_messages_leave_the_state_unchanged() {
+        let (mut alice, mut bob) = pair();
+        let reply = send(&mut bob, "reply");
+        assert_eq!(receive(&mut alice, &reply).unwrap(), "reply");
+        let skipped = send(&mut alice, "one");
+        let data = send(&mut alice, "two");
+
+        let before = bob.clone();
+        for index in [0, HEADER_SIZE - 1, HEADER_SIZE, data.len() - 1] {
+            let mut tampered = data.clone();
+            tampered[index] ^= 1;
+            assert!(receive(&mut bob, &tampered).is_err());
+            assert_eq!(bob.root, before.root);
+            assert_eq!(bob.our_public, before.our_public);
+            assert_eq!(bob.their_public, before.their_public);
+            assert_eq!(bob.recv_chain, before.recv_chain);
+            assert_eq!(bob.recv_count, before.recv_count);
+            assert_eq!(bob.send_chain, before.send_chain);
+            assert_eq!(bob.skipped, before.skipped);
+        }
+
+        assert_eq!(receive(&mut bob, &data).unwrap(), "two");
+        assert_eq!(receive(&mut bob, &skipped).unwrap(), "one");
+    }
+}
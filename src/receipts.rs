@@ -0,0 +1,184 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+use std::net::SocketAddr;
+
+/// Random id every message carries, echoed back in receipts.
+pub type MessageId = [u8; 16];
+
+/// How many sent messages `/status` can still report on.
+const MAX_TRACKED: usize = 200;
+/// How many message ids [`Seen`] remembers.
+const MAX_SEEN: usize = 1000;
+
+/// Where a sent message stands with one recipient. Only ever moves forward.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    Pending,
+    Delivered,
+    Read,
+}
+
+impl Status {
+    pub fn tick(self) -> &'static str {
+        match self {
+            Status::Pending => "·",
+            Status::Delivered => "✓",
+            Status::Read => "✓✓",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Status::Pending => "not delivered",
+            Status::Delivered => "delivered",
+            Status::Read => "read",
+        }
+    }
+}
+
+/// A message we sent and what each recipient has acknowledged.
+pub struct Sent {
+    pub number: u32,
+    id: MessageId,
+    /// What the message was, e.g. the start of the text or a file name.
+    pub summary: String,
+    /// Recipient name and status, by address.
+    pub recipients: BTreeMap<SocketAddr, (String, Status)>,
+}
+
+/// Delivery and read receipts, both for messages we sent and for messages
+/// we have yet to acknowledge as read.
+#[derive(Default)]
+pub struct Receipts {
+    sent: VecDeque<Sent>,
+    next_number: u32,
+    /// Messages shown to the user since they last typed anything.
+    unread: Vec<(SocketAddr, MessageId)>,
+}
+
+impl Receipts {
+    /// Starts tracking a message sent to `recipients`. Returns the short
+    /// number the user can pass to `/status`.
+    pub fn track(
+        &mut self,
+        id: MessageId,
+        summary: String,
+        recipients: &HashMap<SocketAddr, String>,
+    ) -> u32 {
+        self.next_number += 1;
+        if self.sent.len() == MAX_TRACKED {
+            self.sent.pop_front();
+        }
+        self.sent.push_back(Sent {
+            number: self.next_number,
+            id,
+            summary,
+            recipients: recipients
+                .iter()
+                .map(|(addr, name)| (*addr, (name.clone(), Status::Pending)))
+                .collect(),
+        });
+        self.next_number
+    }
+
+    /// Records a receipt from `addr`. Returns the message number and the
+    /// recipient's name if it moved the message forward; receipts from
+    /// peers the message was not sent to are ignored.
+    pub fn update(
+        &mut self,
+        addr: SocketAddr,
+        id: &MessageId,
+        status: Status,
+    ) -> Option<(u32, String)> {
+        let sent = self.sent.iter_mut().rev().find(|sent| sent.id == *id)?;
+        let (name, current) = sent.recipients.get_mut(&addr)?;
+        if *current >= status {
+            return None;
+        }
+        *current = status;
+        Some((sent.number, name.clone()))
+    }
+
+    /// Recipients that have yet to confirm delivery of message `id`.
+    pub fn pending(&self, id: &MessageId) -> Vec<SocketAddr> {
+        self.sent
+            .iter()
+            .rev()
+            .find(|sent| sent.id == *id)
+            .map(|sent| {
+                sent.recipients
+                    .iter()
+                    .filter(|(_, (_, status))| *status == Status::Pending)
+                    .map(|(addr, _)| *addr)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Moves a recipient still waiting for message `id` over to `addr`,
+    /// for a peer that came back on a new address to collect it.
+    pub fn readdress(&mut self, id: &MessageId, name: &str, addr: SocketAddr) {
+        let Some(sent) = self.sent.iter_mut().rev().find(|sent| sent.id == *id) else {
+            return;
+        };
+        let old = sent
+            .recipients
+            .iter()
+            .find(|(old, (recipient, status))| {
+                **old != addr && recipient == name && *status == Status::Pending
+            })
+            .map(|(old, _)| *old);
+        if let Some(entry) = old.and_then(|old| sent.recipients.remove(&old)) {
+            sent.recipients.insert(addr, entry);
+        }
+    }
+
+    /// A sent message by number, or the latest one.
+    pub fn get(&self, number: Option<u32>) -> Option<&Sent> {
+        match number {
+            Some(number) => self.sent.iter().find(|sent| sent.number == number),
+            None => self.sent.back(),
+        }
+    }
+
+    /// Notes a message from `addr` that is owed a read receipt.
+    pub fn mark_unread(&mut self, addr: SocketAddr, id: MessageId) {
+        self.unread.push((addr, id));
+    }
+
+    pub fn take_unread(&mut self) -> Vec<(SocketAddr, MessageId)> {
+        std::mem::take(&mut self.unread)
+    }
+}
+
+/// The most recent message ids handled, for dropping copies that arrive
+/// more than once.
+pub struct Seen<T> {
+    ids: HashSet<T>,
+    order: VecDeque<T>,
+}
+
+impl<T> Default for Seen<T> {
+    fn default() -> Self {
+        Self {
+            ids: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+}
+
+impl<T: Copy + Eq + Hash> Seen<T> {
+    /// Records `id`, returning whether this is the first time it was seen.
+    pub fn insert(&mut self, id: T) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > MAX_SEEN
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.ids.remove(&oldest);
+        }
+        true
+    }
+}
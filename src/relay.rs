@@ -0,0 +1,247 @@
+use chacha20poly1305::{
+    ChaCha20Poly1305, Nonce,
+    aead::{Aead, KeyInit, Payload},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::identity::{self, Identity};
+use crate::receipts::{MessageId, Seen};
+
+const ENVELOPE_KEY_CONTEXT: &str = "rust-chat 2025 relay envelope";
+const ENVELOPE_SIGNATURE_CONTEXT: &[u8] = b"rust-chat 2025 relay envelope signature";
+/// Most relays an envelope may pass through on its way.
+pub const MAX_HOPS: u8 = 3;
+/// How often relays tell their peers who they can reach.
+pub const ROUTE_INTERVAL: Duration = Duration::from_secs(30);
+/// Routes not repeated for this long are dropped.
+const ROUTE_TTL: Duration = Duration::from_secs(90);
+
+type PeerKey = [u8; 32];
+
+/// A message for a peer we cannot reach directly, encrypted to its
+/// identity key and signed with ours. Relays see who it is from and to,
+/// but not what it says.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub id: MessageId,
+    pub from: PeerKey,
+    pub to: PeerKey,
+    /// Relays it may still pass through; not covered by the signature, as
+    /// each relay counts it down.
+    pub hops: u8,
+    ephemeral: [u8; 32],
+    ciphertext: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl Envelope {
+    /// Encrypts `data` for the peer whose identity key is `to`.
+    pub fn seal(
+        identity: &Identity,
+        to: PeerKey,
+        id: MessageId,
+        data: &[u8],
+    ) -> Result<Self, String> {
+        let recipient = identity::exchange_key(&to).ok_or("Invalid identity key")?;
+        let secret = EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+        let ephemeral = PublicKey::from(&secret).to_bytes();
+        let shared = secret.diffie_hellman(&PublicKey::from(recipient));
+        if !shared.was_contributory() {
+            return Err("Invalid identity key".to_string());
+        }
+
+        let from = identity.public_key();
+        let key = envelope_key(shared.as_bytes(), &ephemeral, &from, &to);
+        // The key comes from a fresh ephemeral secret, so a fixed nonce is safe.
+        let ciphertext = ChaCha20Poly1305::new((&key).into())
+            .encrypt(
+                Nonce::from_slice(&[0u8; 12]),
+                Payload {
+                    msg: data,
+                    aad: &id,
+                },
+            )
+            .map_err(|e| format!("Encryption error: {}", e))?;
+
+        let mut envelope = Self {
+            id,
+            from,
+            to,
+            hops: MAX_HOPS,
+            ephemeral,
+            ciphertext,
+            signature: Vec::new(),
+        };
+        envelope.signature = identity.sign(&envelope.signed_data()).to_vec();
+        Ok(envelope)
+    }
+
+    /// Checks the sender's signature and decrypts the envelope, which must
+    /// be addressed to `identity`.
+    pub fn open(&self, identity: &Identity) -> Result<Vec<u8>, String> {
+        if !identity::verify(&self.from, &self.signed_data(), &self.signature) {
+            return Err("Invalid signature".to_string());
+        }
+        let shared = identity
+            .agree(&self.ephemeral)
+            .ok_or("Invalid ephemeral key")?;
+        let key = envelope_key(&shared, &self.ephemeral, &self.from, &self.to);
+
+        ChaCha20Poly1305::new((&key).into())
+            .decrypt(
+                Nonce::from_slice(&[0u8; 12]),
+                Payload {
+                    msg: &self.ciphertext,
+                    aad: &self.id,
+                },
+            )
+            .map_err(|e| format!("Decryption error: {}", e))
+    }
+
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = ENVELOPE_SIGNATURE_CONTEXT.to_vec();
+        data.extend_from_slice(&self.id);
+        data.extend_from_slice(&self.from);
+        data.extend_from_slice(&self.to);
+        data.extend_from_slice(&self.ephemeral);
+        data.extend_from_slice(&self.ciphertext);
+        data
+    }
+}
+
+fn envelope_key(shared: &[u8; 32], ephemeral: &[u8; 32], from: &PeerKey, to: &PeerKey) -> [u8; 32] {
+    let mut material = shared.to_vec();
+    material.extend_from_slice(ephemeral);
+    material.extend_from_slice(from);
+    material.extend_from_slice(to);
+    blake3::derive_key(ENVELOPE_KEY_CONTEXT, &material)
+}
+
+/// A peer a relay can reach, as it announces it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteEntry {
+    pub name: String,
+    pub key: PeerKey,
+    /// Relays between the announcing relay's neighbour and the peer,
+    /// counting the announcing relay itself.
+    pub hops: u8,
+    pub rooms: Vec<String>,
+}
+
+/// How to reach a peer through a relay.
+pub struct Route {
+    pub name: String,
+    /// The relay to hand envelopes to.
+    pub via: SocketAddr,
+    pub hops: u8,
+    pub rooms: BTreeSet<String>,
+    updated: Instant,
+}
+
+/// Peers reachable only through relays, learned from relays' route
+/// announcements, and the envelopes already seen.
+#[derive(Default)]
+pub struct Mesh {
+    relaying: bool,
+    routes: HashMap<(PeerKey, SocketAddr), Route>,
+    seen: Seen<(MessageId, PeerKey)>,
+}
+
+impl Mesh {
+    /// Forward envelopes for others and announce who we can reach.
+    pub fn enable_relaying(&mut self) {
+        self.relaying = true;
+    }
+
+    pub fn is_relaying(&self) -> bool {
+        self.relaying
+    }
+
+    /// Replaces the routes learned from the relay at `via`.
+    pub fn update(&mut self, via: SocketAddr, entries: Vec<RouteEntry>) {
+        self.forget_via(via);
+        let updated = Instant::now();
+        for entry in entries {
+            if entry.hops == 0 || entry.hops > MAX_HOPS {
+                continue;
+            }
+            self.routes.insert(
+                (entry.key, via),
+                Route {
+                    name: entry.name,
+                    via,
+                    hops: entry.hops,
+                    rooms: crate::rooms::parse_announced(&entry.rooms),
+                    updated,
+                },
+            );
+        }
+    }
+
+    /// Drops every route through `via`, e.g. once that relay is gone.
+    pub fn forget_via(&mut self, via: SocketAddr) {
+        self.routes.retain(|(_, relay), _| *relay != via);
+    }
+
+    /// The shortest route to `key`, avoiding the relay at `except`.
+    pub fn best(&self, key: &PeerKey, except: Option<SocketAddr>) -> Option<&Route> {
+        self.routes
+            .iter()
+            .filter(|((peer, via), route)| {
+                peer == key && Some(*via) != except && route.updated.elapsed() < ROUTE_TTL
+            })
+            .map(|(_, route)| route)
+            .min_by_key(|route| route.hops)
+    }
+
+    /// Every peer with a route, by identity key, with its shortest route.
+    pub fn reachable(&self) -> Vec<(PeerKey, &Route)> {
+        let keys: BTreeSet<PeerKey> = self.routes.keys().map(|(key, _)| *key).collect();
+        keys.into_iter()
+            .filter_map(|key| Some((key, self.best(&key, None)?)))
+            .collect()
+    }
+
+    /// What to tell the peer `to` at `addr` we can reach: our direct peers
+    /// and, one hop further, what other relays told us, leaving out routes
+    /// that lead back through `addr`.
+    pub fn announcement(
+        &self,
+        to: &PeerKey,
+        addr: SocketAddr,
+        direct: &[RouteEntry],
+    ) -> Vec<RouteEntry> {
+        let mut entries: Vec<RouteEntry> = direct
+            .iter()
+            .filter(|entry| entry.key != *to)
+            .cloned()
+            .collect();
+        for (key, _) in self.reachable() {
+            if key == *to || entries.iter().any(|entry| entry.key == key) {
+                continue;
+            }
+            if let Some(route) = self.best(&key, Some(addr))
+                && route.hops < MAX_HOPS
+            {
+                entries.push(RouteEntry {
+                    name: route.name.clone(),
+                    key,
+                    hops: route.hops + 1,
+                    rooms: route.rooms.iter().cloned().collect(),
+                });
+            }
+        }
+        entries
+    }
+
+    /// Whether this is the first copy of `envelope` to reach us. The same
+    /// message goes out in one envelope per recipient, so they are told
+    /// apart by id and recipient.
+    pub fn first_sighting(&mut self, envelope: &Envelope) -> bool {
+        self.seen.insert((envelope.id, envelope.to))
+    }
+}
@@ -0,0 +1,90 @@
+use std::collections::{BTreeSet, HashMap};
+
+const MAX_ROOM_NAME: usize = 24;
+/// Bounds the room list every peer is sent and keeps.
+const MAX_JOINED_ROOMS: usize = 8;
+
+/// Rooms this instance has joined and the one typed messages go to. With
+/// no current room, messages go to the lobby that every peer is in.
+#[derive(Default)]
+pub struct Rooms {
+    joined: BTreeSet<String>,
+    current: Option<String>,
+    /// Passwords for the joined rooms that have one.
+    passwords: HashMap<String, String>,
+}
+
+impl Rooms {
+    /// Joins `room` if needed and makes it the current room. A password
+    /// replaces any set earlier, but rejoining without one keeps it.
+    pub fn join(&mut self, room: &str, password: Option<String>) -> Result<(), String> {
+        if !self.joined.contains(room) && self.joined.len() >= MAX_JOINED_ROOMS {
+            return Err(format!(
+                "You can be in at most {} rooms, /leave one first",
+                MAX_JOINED_ROOMS
+            ));
+        }
+        self.joined.insert(room.to_string());
+        self.current = Some(room.to_string());
+        if let Some(password) = password {
+            self.passwords.insert(room.to_string(), password);
+        }
+        Ok(())
+    }
+
+    /// Leaves `room`, falling back to the lobby if it was the current room.
+    pub fn leave(&mut self, room: &str) -> bool {
+        if self.current.as_deref() == Some(room) {
+            self.current = None;
+        }
+        self.passwords.remove(room);
+        self.joined.remove(room)
+    }
+
+    pub fn current(&self) -> Option<&str> {
+        self.current.as_deref()
+    }
+
+    pub fn is_joined(&self, room: &str) -> bool {
+        self.joined.contains(room)
+    }
+
+    pub fn password(&self, room: &str) -> Option<&str> {
+        self.passwords.get(room).map(String::as_str)
+    }
+
+    pub fn joined(&self) -> &BTreeSet<String> {
+        &self.joined
+    }
+}
+
+/// Normalises a room name typed by the user to `#name`. Names are lowercase
+/// letters, digits, `-` and `_`.
+pub fn parse_room(input: &str) -> Result<String, String> {
+    let name = input.trim().trim_start_matches('#').to_lowercase();
+    if name.is_empty() {
+        return Err("Room name cannot be empty".to_string());
+    }
+    if name.len() > MAX_ROOM_NAME {
+        return Err(format!(
+            "Room names are at most {} characters",
+            MAX_ROOM_NAME
+        ));
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+    {
+        return Err("Room names may only use letters, digits, '-' and '_'".to_string());
+    }
+    Ok(format!("#{}", name))
+}
+
+/// Rooms a peer says it is in. Malformed entries are skipped.
+pub fn parse_announced(rooms: &[String]) -> BTreeSet<String> {
+    rooms
+        .iter()
+        .filter_map(|room| parse_room(room).ok())
+        .take(MAX_JOINED_ROOMS)
+        .collect()
+}
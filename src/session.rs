@@ -0,0 +1,641 @@
+use chacha20poly1305::{
+    ChaCha20Poly1305, Nonce,
+    aead::{Aead, KeyInit, Payload},
+};
+use rand::Rng;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::frame::{self, FrameHeader, FrameType, IncompatibleVersion};
+use crate::identity::{self, Identity};
+use crate::ratchet::Ratchet;
+use crate::{MAX_MESSAGE_SIZE, Message, MessageType, NONCE_SIZE};
+
+const INITIATOR_KEY_CONTEXT: &str = "rust-chat 2025 session key initiator->responder";
+const RESPONDER_KEY_CONTEXT: &str = "rust-chat 2025 session key responder->initiator";
+const TRANSCRIPT_CONTEXT: &str = "rust-chat 2025 handshake transcript";
+const RATCHET_ROOT_CONTEXT: &str = "rust-chat 2025 ratchet root";
+const SEQUENCE_SIZE: usize = 8;
+/// How far behind the newest frame an earlier one may still be accepted.
+const REPLAY_WINDOW: u64 = 64;
+
+/// The other side hung up before the handshake began, as dialers do with
+/// the addresses that lose a race to connect.
+#[derive(Debug)]
+pub struct NoHandshake;
+
+impl std::fmt::Display for NoHandshake {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Connection closed before the handshake")
+    }
+}
+
+impl std::error::Error for NoHandshake {}
+
+/// What we present about ourselves during a handshake.
+pub struct LocalPeer {
+    pub identity: Arc<Identity>,
+    pub name: String,
+    pub listen_port: u16,
+}
+
+/// A double ratchet seeded by the handshake of a single TCP connection,
+/// along with the authenticated identity of the remote side.
+pub struct Session {
+    /// Protocol version agreed during the handshake.
+    version: u8,
+    ratchet: Mutex<Ratchet>,
+    /// Sequence number of the last frame sent; frames are numbered from 1.
+    sent: AtomicU64,
+    received: Mutex<ReplayWindow>,
+    pub peer_name: String,
+    pub peer_key: [u8; 32],
+    /// Port the remote side accepts connections on, which for inbound
+    /// connections differs from the ephemeral source port.
+    pub peer_listen_port: u16,
+}
+
+/// Why an incoming frame was dropped.
+pub enum FrameError {
+    /// The frame's sequence number was seen before, or is too far behind
+    /// the newest one to tell.
+    Replayed(u64),
+    /// The frame was malformed or did not authenticate.
+    Invalid,
+}
+
+/// Sliding window over received sequence numbers. Anything accepted
+/// before, or older than the window, is a replay.
+#[derive(Default)]
+struct ReplayWindow {
+    highest: u64,
+    /// Bit `i` is set once `highest - i` has been accepted.
+    seen: u64,
+}
+
+/// Static per-direction keys that protect the rest of the handshake.
+struct HandshakeCipher {
+    version: u8,
+    send: ChaCha20Poly1305,
+    recv: ChaCha20Poly1305,
+}
+
+/// What the remote side proved about itself during the handshake.
+struct PeerIdentity {
+    name: String,
+    key: [u8; 32],
+    listen_port: u16,
+    ratchet_key: x25519_dalek::PublicKey,
+}
+
+/// The remote side's opening message.
+struct KeyExchange {
+    public: PublicKey,
+    /// Oldest and newest protocol versions it speaks.
+    versions: (u8, u8),
+}
+
+impl Session {
+    /// Runs the handshake as the side that opened the connection.
+    pub async fn initiate<S>(
+        stream: &mut S,
+        local: &LocalPeer,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        Self::handshake(stream, local, true).await
+    }
+
+    /// Runs the handshake as the side that accepted the connection.
+    pub async fn accept<S>(
+        stream: &mut S,
+        local: &LocalPeer,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        Self::handshake(stream, local, false).await
+    }
+
+    /// Ephemeral X25519 exchange and version negotiation, followed by each
+    /// side signing the transcript with its long-term identity key over the
+    /// new session and sharing its first ratchet key.
+    async fn handshake<S>(
+        stream: &mut S,
+        local: &LocalPeer,
+        is_initiator: bool,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let secret = EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+        let public = PublicKey::from(&secret);
+
+        let ours = (frame::MIN_VERSION, frame::MAX_VERSION);
+        let exchange = if is_initiator {
+            send_key_exchange(stream, &public).await?;
+            recv_key_exchange(stream).await?
+        } else {
+            // Answer even a peer we cannot talk to, so it can tell its user why.
+            let exchange = recv_key_exchange(stream).await?;
+            send_key_exchange(stream, &public).await?;
+            exchange
+        };
+        let version = frame::negotiate(ours, exchange.versions).ok_or(IncompatibleVersion {
+            versions: Some(exchange.versions),
+        })?;
+        let peer_public = exchange.public;
+
+        let (initiator, responder) = if is_initiator {
+            (&public, &peer_public)
+        } else {
+            (&peer_public, &public)
+        };
+        let (initiator_versions, responder_versions) = if is_initiator {
+            (ours, exchange.versions)
+        } else {
+            (exchange.versions, ours)
+        };
+
+        // Bind both public keys into the derivation so each direction gets
+        // a distinct key that is tied to this particular exchange.
+        let shared = secret.diffie_hellman(&peer_public);
+        let mut material = Vec::with_capacity(96);
+        material.extend_from_slice(shared.as_bytes());
+        material.extend_from_slice(initiator.as_bytes());
+        material.extend_from_slice(responder.as_bytes());
+
+        let i2r = blake3::derive_key(INITIATOR_KEY_CONTEXT, &material);
+        let r2i = blake3::derive_key(RESPONDER_KEY_CONTEXT, &material);
+        let root = blake3::derive_key(RATCHET_ROOT_CONTEXT, &material);
+        let (send, recv) = if is_initiator { (i2r, r2i) } else { (r2i, i2r) };
+
+        let cipher = HandshakeCipher {
+            version,
+            send: ChaCha20Poly1305::new((&send).into()),
+            recv: ChaCha20Poly1305::new((&recv).into()),
+        };
+
+        // Signing the advertised versions stops anyone in the middle from
+        // forcing an older protocol on both sides.
+        let mut transcript = Vec::with_capacity(68);
+        transcript.extend_from_slice(initiator.as_bytes());
+        transcript.extend_from_slice(responder.as_bytes());
+        transcript.extend_from_slice(&[
+            initiator_versions.0,
+            initiator_versions.1,
+            responder_versions.0,
+            responder_versions.1,
+        ]);
+        let transcript = blake3::derive_key(TRANSCRIPT_CONTEXT, &transcript);
+
+        let (ratchet_secret, ratchet_public) = Ratchet::keypair();
+        let peer = if is_initiator {
+            cipher
+                .send_identity(stream, local, &transcript, true, &ratchet_public)
+                .await?;
+            cipher.recv_identity(stream, &transcript, false).await?
+        } else {
+            let peer = cipher.recv_identity(stream, &transcript, true).await?;
+            cipher
+                .send_identity(stream, local, &transcript, false, &ratchet_public)
+                .await?;
+            peer
+        };
+
+        let ratchet = if is_initiator {
+            Ratchet::initiator(root, ratchet_secret, peer.ratchet_key)
+        } else {
+            Ratchet::responder(root, ratchet_secret, peer.ratchet_key)
+        };
+
+        Ok(Self {
+            version,
+            ratchet: Mutex::new(ratchet),
+            sent: AtomicU64::new(0),
+            received: Mutex::new(ReplayWindow::default()),
+            peer_name: peer.name,
+            peer_key: peer.key,
+            peer_listen_port: peer.listen_port,
+        })
+    }
+
+    /// Encrypts the next frame behind its header and sequence number. Both
+    /// are authenticated with the message, so neither can be changed to
+    /// slip an old frame past [`Session::decrypt`]. With `pad`, the message
+    /// is first padded to its size bucket.
+    pub fn encrypt(&self, data: &[u8], pad: bool) -> Result<Vec<u8>, String> {
+        let mut ratchet = self.ratchet.lock().unwrap();
+        let sequence = self.sent.fetch_add(1, Ordering::Relaxed) + 1;
+
+        let mut header = FrameHeader::new(self.version, FrameType::Data);
+        let padded;
+        let data = if pad {
+            header = header.with_flags(frame::FLAG_PADDED);
+            padded = frame::pad(data);
+            &padded[..]
+        } else {
+            data
+        };
+
+        let mut result = header.to_bytes().to_vec();
+        result.extend_from_slice(&sequence.to_be_bytes());
+        let ciphertext = ratchet.encrypt(data, &result)?;
+        result.extend_from_slice(&ciphertext);
+        Ok(result)
+    }
+
+    /// Decrypts a frame, rejecting any whose sequence number was already
+    /// used or has fallen out of the window. Cover traffic decrypts to an
+    /// empty message.
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, FrameError> {
+        let header = match FrameHeader::parse(data) {
+            Some((header, _)) if header.is(self.version, FrameType::Data) => header,
+            _ => return Err(FrameError::Invalid),
+        };
+        if data.len() < frame::HEADER_SIZE + SEQUENCE_SIZE {
+            return Err(FrameError::Invalid);
+        }
+        let (associated, frame) = data.split_at(frame::HEADER_SIZE + SEQUENCE_SIZE);
+        let sequence = u64::from_be_bytes(associated[frame::HEADER_SIZE..].try_into().unwrap());
+
+        let mut received = self.received.lock().unwrap();
+        if !received.is_fresh(sequence) {
+            return Err(FrameError::Replayed(sequence));
+        }
+        let plaintext = self
+            .ratchet
+            .lock()
+            .unwrap()
+            .decrypt(frame, associated)
+            .map_err(|_| FrameError::Invalid)?;
+        // Only authenticated frames move the window, so forged sequence
+        // numbers cannot push genuine frames out of it.
+        received.accept(sequence);
+
+        if header.flags & frame::FLAG_PADDED == 0 {
+            return Ok(plaintext);
+        }
+        frame::unpad(&plaintext)
+            .map(<[u8]>::to_vec)
+            .ok_or(FrameError::Invalid)
+    }
+}
+
+impl ReplayWindow {
+    fn is_fresh(&self, sequence: u64) -> bool {
+        if sequence > self.highest {
+            return true;
+        }
+        let offset = self.highest - sequence;
+        sequence != 0 && offset < REPLAY_WINDOW && self.seen & (1 << offset) == 0
+    }
+
+    fn accept(&mut self, sequence: u64) {
+        if sequence > self.highest {
+            let shift = sequence - self.highest;
+            self.seen = if shift < REPLAY_WINDOW {
+                self.seen << shift
+            } else {
+                0
+            };
+            self.seen |= 1;
+            self.highest = sequence;
+        } else {
+            self.seen |= 1 << (self.highest - sequence);
+        }
+    }
+}
+
+impl HandshakeCipher {
+    async fn send_identity<S>(
+        &self,
+        stream: &mut S,
+        local: &LocalPeer,
+        transcript: &[u8; 32],
+        is_initiator: bool,
+        ratchet_key: &x25519_dalek::PublicKey,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let message = Message::new(
+            local.name.clone(),
+            MessageType::Identity {
+                public_key: local.identity.public_key().to_vec(),
+                signature: local
+                    .identity
+                    .sign(&signed_transcript(transcript, is_initiator))
+                    .to_vec(),
+                listen_port: local.listen_port,
+                ratchet_key: ratchet_key.as_bytes().to_vec(),
+            },
+        );
+
+        let header = FrameHeader::new(self.version, FrameType::Identity);
+        let encrypted = self.encrypt(&bincode::serialize(&message)?, &header.to_bytes())?;
+        write_frame(stream, &header.wrap(&encrypted)).await
+    }
+
+    async fn recv_identity<S>(
+        &self,
+        stream: &mut S,
+        transcript: &[u8; 32],
+        from_initiator: bool,
+    ) -> Result<PeerIdentity, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let frame = read_frame(stream)
+            .await?
+            .ok_or("Connection closed during handshake")?;
+        let (header, body) = match FrameHeader::parse(&frame) {
+            Some((header, body))
+                if header == FrameHeader::new(self.version, FrameType::Identity) =>
+            {
+                (header, body)
+            }
+            _ => return Err("Expected identity".into()),
+        };
+        let decrypted = self.decrypt(body, &header.to_bytes())?;
+        let message = bincode::deserialize::<Message>(&decrypted)?;
+
+        let MessageType::Identity {
+            public_key,
+            signature,
+            listen_port,
+            ratchet_key,
+        } = message.msg_type
+        else {
+            return Err("Expected identity".into());
+        };
+
+        let public_key: [u8; 32] = public_key
+            .try_into()
+            .map_err(|_| "Invalid identity key length")?;
+        let ratchet_key: [u8; 32] = ratchet_key
+            .try_into()
+            .map_err(|_| "Invalid ratchet key length")?;
+
+        if !identity::verify(
+            &public_key,
+            &signed_transcript(transcript, from_initiator),
+            &signature,
+        ) {
+            return Err(format!("Bad identity signature from {}", message.sender).into());
+        }
+
+        Ok(PeerIdentity {
+            name: message.sender,
+            key: public_key,
+            listen_port,
+            ratchet_key: ratchet_key.into(),
+        })
+    }
+
+    fn encrypt(&self, data: &[u8], header: &[u8]) -> Result<Vec<u8>, String> {
+        let mut nonce_bytes = [0u8; NONCE_SIZE];
+        rand::thread_rng().fill(&mut nonce_bytes);
+        let nonce = Nonce::from_slice(&nonce_bytes);
+
+        let ciphertext = self
+            .send
+            .encrypt(
+                nonce,
+                Payload {
+                    msg: data,
+                    aad: header,
+                },
+            )
+            .map_err(|e| format!("Encryption error: {}", e))?;
+
+        let mut result = nonce_bytes.to_vec();
+        result.extend_from_slice(&ciphertext);
+        Ok(result)
+    }
+
+    fn decrypt(&self, data: &[u8], header: &[u8]) -> Result<Vec<u8>, String> {
+        if data.len() < NONCE_SIZE {
+            return Err("Frame too short".to_string());
+        }
+
+        let nonce = Nonce::from_slice(&data[..NONCE_SIZE]);
+        self.recv
+            .decrypt(
+                nonce,
+                Payload {
+                    msg: &data[NONCE_SIZE..],
+                    aad: header,
+                },
+            )
+            .map_err(|e| format!("Decryption error: {}", e))
+    }
+}
+
+/// The role byte stops one side's signature being reflected back as the other's.
+fn signed_transcript(transcript: &[u8; 32], is_initiator: bool) -> Vec<u8> {
+    let mut data = Vec::with_capacity(33);
+    data.push(is_initiator as u8);
+    data.extend_from_slice(transcript);
+    data
+}
+
+/// Sent in the clear, so it carries no name; names are only exchanged once
+/// the session is encrypted.
+async fn send_key_exchange<S>(
+    stream: &mut S,
+    public: &PublicKey,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncWrite + Unpin,
+{
+    let message = Message::new(
+        String::new(),
+        MessageType::KeyExchange {
+            public_key: public.as_bytes().to_vec(),
+            min_version: frame::MIN_VERSION,
+        },
+    );
+
+    // The header carries the newest version we speak.
+    let header = FrameHeader::new(frame::MAX_VERSION, FrameType::KeyExchange);
+    write_frame(stream, &header.wrap(&bincode::serialize(&message)?)).await
+}
+
+async fn recv_key_exchange<S>(
+    stream: &mut S,
+) -> Result<KeyExchange, Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + Unpin,
+{
+    let frame = read_frame(stream).await?.ok_or(NoHandshake)?;
+
+    let Some((header, body)) = FrameHeader::parse(&frame) else {
+        return Err(IncompatibleVersion { versions: None }.into());
+    };
+    if header.frame_type != FrameType::KeyExchange {
+        return Err("Expected key exchange".into());
+    }
+
+    let message = bincode::deserialize::<Message>(body)?;
+    match message.msg_type {
+        MessageType::KeyExchange {
+            public_key,
+            min_version,
+        } => {
+            let bytes: [u8; 32] = public_key
+                .try_into()
+                .map_err(|_| "Invalid public key length")?;
+            Ok(KeyExchange {
+                public: PublicKey::from(bytes),
+                versions: (min_version, header.version),
+            })
+        }
+        _ => Err("Expected key exchange".into()),
+    }
+}
+
+/// Writes a length-prefixed frame.
+pub async fn write_frame<S>(
+    stream: &mut S,
+    data: &[u8],
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream.write_u32(data.len() as u32).await?;
+    stream.write_all(data).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Reads a length-prefixed frame, returning `None` once the peer hangs up.
+pub async fn read_frame<S>(
+    stream: &mut S,
+) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + Unpin,
+{
+    let len = match stream.read_u32().await {
+        Ok(l) => l as usize,
+        Err(_) => return Ok(None),
+    };
+
+    if len == 0 || len > MAX_MESSAGE_SIZE {
+        return Err(format!("Invalid frame length: {}", len).into());
+    }
+
+    let mut data = vec![0u8; len];
+    stream.read_exact(&mut data).await?;
+    Ok(Some(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(accepted: &[u64]) -> ReplayWindow {
+        let mut window = ReplayWindow::default();
+        for sequence in accepted {
+            assert!(window.is_fresh(*sequence), "{} should be fresh", sequence);
+            window.accept(*sequence);
+        }
+        window
+    }
+
+    fn local(name: &str) -> LocalPeer {
+        let dir = std::env::temp_dir().join(format!(
+            "rust-chat-session-test-{}",
+            hex::encode(rand::random::<[u8; 8]>())
+        ));
+        let identity = Identity::load_or_create(&dir).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        LocalPeer {
+            identity: Arc::new(identity),
+            name: name.to_string(),
+            listen_port: 0,
+        }
+    }
+
+    async fn pair() -> (Session, Session) {
+        let (mut a, mut b) = tokio::io::duplex(64 * 1024);
+        let (alice, bob) = (local("alice"), local("bob"));
+        let (initiator, responder) = tokio::join!(
+            Session::initiate(&mut a, &alice),
+            Session::accept(&mut b, &bob)
+        );
+        (initiator.unwrap(), responder.unwrap())
+    }
+
+    #[test]
+    fn sequence_zero_is_never_fresh() {
+        assert!(!ReplayWindow::default().is_fresh(0));
+        assert!(!window(&[1, 2]).is_fresh(0));
+    }
+
+    #[test]
+    fn duplicates_are_rejected() {
+        let window = window(&[1, 3, 2]);
+        for sequence in [1, 2, 3] {
+            assert!(!window.is_fresh(sequence));
+        }
+        assert!(window.is_fresh(4));
+    }
+
+    #[test]
+    fn late_frames_are_accepted_within_the_window() {
+        let window = window(&[100]);
+        assert!(window.is_fresh(100 - 63));
+        assert!(!window.is_fresh(100 - 64));
+    }
+
+    #[test]
+    fn window_shifts_keep_what_was_seen() {
+        let window = window(&[1, 64]);
+        // 1 is now 63 behind and still remembered.
+        assert!(!window.is_fresh(1));
+        assert!(window.is_fresh(2));
+    }
+
+    #[test]
+    fn shifting_by_the_window_or_more_forgets_everything_behind() {
+        let shifted = window(&[1, 65]);
+        assert!(!shifted.is_fresh(1));
+        assert!(shifted.is_fresh(2));
+
+        let jumped = window(&[5, 5000]);
+        assert!(!jumped.is_fresh(5000));
+        assert!(jumped.is_fresh(4999));
+        assert!(!jumped.is_fresh(5000 - 64));
+    }
+
+    #[tokio::test]
+    async fn frames_round_trip_once() {
+        let (alice, bob) = pair().await;
+        let frame = alice.encrypt(b"hello", false).unwrap();
+        assert_eq!(bob.decrypt(&frame).ok().unwrap(), b"hello");
+        assert!(matches!(bob.decrypt(&frame), Err(FrameError::Replayed(1))));
+    }
+
+    #[tokio::test]
+    async fn frames_that_fail_authentication_do_not_move_the_window() {
+        let (alice, bob) = pair().await;
+        let frame = alice.encrypt(b"hello", false).unwrap();
+
+        let mut tampered = frame.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(bob.decrypt(&tampered), Err(FrameError::Invalid)));
+
+        // A forged sequence number far ahead would push frame 1 out of the
+        // window if it counted.
+        let mut forged = frame.clone();
+        forged[frame::HEADER_SIZE..frame::HEADER_SIZE + SEQUENCE_SIZE]
+            .copy_from_slice(&1000u64.to_be_bytes());
+        assert!(matches!(bob.decrypt(&forged), Err(FrameError::Invalid)));
+
+        assert_eq!(bob.decrypt(&frame).ok().unwrap(), b"hello");
+    }
+}
@@ -0,0 +1,993 @@
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{Mutex, mpsc};
+use tokio::task::JoinHandle;
+
+use crate::connection::ConnectionManager;
+use crate::{Message, MessageType};
+
+/// Plaintext bytes per chunk; each chunk travels as its own encrypted frame.
+/// Leaves room for the message around it within a 64 KB padding bucket.
+pub const CHUNK_SIZE: usize = 63 * 1024;
+/// Leading bytes inspected when sniffing a file's type.
+const SNIFF_SIZE: usize = 8 * 1024;
+const UNKNOWN_MIME: &str = "application/octet-stream";
+/// File messages waiting for the transfer task, about 16 MB of chunks.
+const INBOUND_QUEUE_SIZE: usize = 256;
+
+pub type TransferId = [u8; 16];
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub enum FileKind {
+    Image,
+    Video,
+    File,
+}
+
+impl FileKind {
+    pub fn label(&self) -> &'static str {
+        match self {
+            FileKind::Image => "image",
+            FileKind::Video => "video",
+            FileKind::File => "file",
+        }
+    }
+
+    /// Whether sniffed content is acceptable for this kind; `/img` and `/vid`
+    /// only send what really is an image or video.
+    fn accepts(&self, mime: &str) -> bool {
+        match self {
+            FileKind::Image => mime.starts_with("image/"),
+            FileKind::Video => mime.starts_with("video/"),
+            FileKind::File => true,
+        }
+    }
+}
+
+type PeerKey = [u8; 32];
+
+/// Who a file is offered to, and so who may fetch it.
+pub struct Audience {
+    /// Recipients by identity key.
+    pub recipients: HashSet<PeerKey>,
+    /// Lobby offers may be fetched by any peer.
+    pub public: bool,
+}
+
+struct OutgoingFile {
+    path: PathBuf,
+    filename: String,
+    size: u64,
+    audience: Audience,
+    /// Recipients yet to receive or decline the file. It stops being served
+    /// once none are left.
+    pending: HashSet<PeerKey>,
+}
+
+impl OutgoingFile {
+    fn allows(&self, peer_key: &PeerKey) -> bool {
+        self.audience.public || self.audience.recipients.contains(peer_key)
+    }
+}
+
+/// An offer waiting for the user to `/accept` or `/reject` it.
+struct PendingOffer {
+    number: u32,
+    from: SocketAddr,
+    sender: String,
+    transfer_id: TransferId,
+    filename: String,
+    size: u64,
+    hash: [u8; 32],
+    kind: FileKind,
+    mime: String,
+}
+
+struct IncomingFile {
+    from: SocketAddr,
+    sender: String,
+    filename: String,
+    mime: String,
+    size: u64,
+    hash: [u8; 32],
+    received: u64,
+    part_path: PathBuf,
+    progress: Progress,
+}
+
+/// A file message and who it came from, queued for the transfer task.
+struct Inbound {
+    from: SocketAddr,
+    peer_key: PeerKey,
+    message: Message,
+    peer_name: String,
+}
+
+/// Streams files to peers in encrypted chunks and reassembles incoming
+/// ones on disk, resuming from the partial file after a disconnect.
+pub struct Transfers {
+    name: String,
+    download_dir: PathBuf,
+    connections: Arc<ConnectionManager>,
+    outgoing: Mutex<HashMap<TransferId, OutgoingFile>>,
+    offers: Mutex<Vec<PendingOffer>>,
+    next_offer: AtomicU32,
+    incoming: Mutex<HashMap<TransferId, IncomingFile>>,
+    /// Running upload tasks, so a resume request replaces the stale stream.
+    streams: Mutex<HashMap<(SocketAddr, TransferId), JoinHandle<()>>>,
+    inbound: mpsc::Sender<Inbound>,
+}
+
+impl Transfers {
+    /// Creates the transfer state and starts the task that handles file
+    /// messages, one at a time in the order they arrived.
+    pub fn new(
+        name: String,
+        download_dir: PathBuf,
+        connections: Arc<ConnectionManager>,
+    ) -> Arc<Self> {
+        let (inbound, mut queue) = mpsc::channel(INBOUND_QUEUE_SIZE);
+        let transfers = Arc::new(Self {
+            name,
+            download_dir,
+            connections,
+            outgoing: Mutex::new(HashMap::new()),
+            offers: Mutex::new(Vec::new()),
+            next_offer: AtomicU32::new(1),
+            incoming: Mutex::new(HashMap::new()),
+            streams: Mutex::new(HashMap::new()),
+            inbound,
+        });
+
+        let worker = transfers.clone();
+        tokio::spawn(async move {
+            while let Some(inbound) = queue.recv().await {
+                let Inbound {
+                    from,
+                    peer_key,
+                    message,
+                    peer_name,
+                } = inbound;
+                worker.process(from, &peer_key, &message, &peer_name).await;
+            }
+        });
+        transfers
+    }
+
+    /// Sniffs and hashes the file at `path` and registers it for streaming
+    /// to `audience`. The returned offer is what gets sent; peers pull the
+    /// data with `FileRequest`.
+    pub async fn offer(
+        &self,
+        path: &str,
+        kind: FileKind,
+        audience: Audience,
+    ) -> std::io::Result<MessageType> {
+        let path = PathBuf::from(path);
+        let size = tokio::fs::metadata(&path).await?.len();
+        let filename = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("unknown")
+            .to_string();
+
+        let mime = sniff_file(&path).await?;
+        if !kind.accepts(&mime) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{} does not look like {} {} (detected {})",
+                    filename,
+                    if matches!(kind, FileKind::Image) {
+                        "an"
+                    } else {
+                        "a"
+                    },
+                    kind.label(),
+                    mime
+                ),
+            ));
+        }
+
+        let hash = hash_file(&path).await?;
+
+        // Deterministic ids let a re-sent file pick up the receiver's partial copy.
+        let mut id_material = hash.to_vec();
+        id_material.extend_from_slice(filename.as_bytes());
+        let mut transfer_id = [0u8; 16];
+        transfer_id.copy_from_slice(&blake3::hash(&id_material).as_bytes()[..16]);
+
+        // Offering the same file again widens who may fetch it.
+        let mut outgoing = self.outgoing.lock().await;
+        let file = outgoing.entry(transfer_id).or_insert_with(|| OutgoingFile {
+            path,
+            filename: filename.clone(),
+            size,
+            audience: Audience {
+                recipients: HashSet::new(),
+                public: false,
+            },
+            pending: HashSet::new(),
+        });
+        file.audience.public |= audience.public;
+        file.pending.extend(&audience.recipients);
+        file.audience.recipients.extend(audience.recipients);
+        drop(outgoing);
+
+        Ok(MessageType::FileOffer {
+            transfer_id,
+            filename,
+            size,
+            hash: hash.to_vec(),
+            kind,
+            mime,
+        })
+    }
+
+    /// Hands transfer traffic from the peer listening on `from`, whose
+    /// identity key is `peer_key`, to the transfer task. Disk writes and
+    /// hashing happen there, so they never hold up other peers' messages.
+    pub async fn handle(
+        &self,
+        from: SocketAddr,
+        peer_key: &PeerKey,
+        message: &Message,
+        peer_name: &str,
+    ) {
+        let inbound = Inbound {
+            from,
+            peer_key: *peer_key,
+            message: message.clone(),
+            peer_name: peer_name.to_string(),
+        };
+        let _ = self.inbound.send(inbound).await;
+    }
+
+    async fn process(
+        self: &Arc<Self>,
+        from: SocketAddr,
+        peer_key: &PeerKey,
+        message: &Message,
+        peer_name: &str,
+    ) {
+        match &message.msg_type {
+            MessageType::FileOffer {
+                transfer_id,
+                filename,
+                size,
+                hash,
+                kind,
+                mime,
+            } => {
+                let Ok(hash) = hash.as_slice().try_into() else {
+                    return;
+                };
+                // Shown and kept as it will be saved, so an offer cannot
+                // fake the prompt or have one name approved and another
+                // written.
+                let filename = sanitize_filename(filename);
+                let mime = sanitize_mime(mime);
+                let number = self.next_offer.fetch_add(1, Ordering::Relaxed);
+                let privately = if message.recipient.is_some() {
+                    " you privately"
+                } else {
+                    ""
+                };
+                let room = match &message.channel {
+                    Some(room) => format!("{} ", room.cyan()),
+                    None => String::new(),
+                };
+                println!(
+                    "{}{} wants to send{} {} {} ({}, {}) - {} or {}",
+                    room,
+                    peer_name.blue(),
+                    privately,
+                    kind.label(),
+                    filename.yellow(),
+                    mime,
+                    format_size(*size),
+                    format!("/accept {}", number).cyan(),
+                    format!("/reject {}", number).cyan()
+                );
+                self.offers.lock().await.push(PendingOffer {
+                    number,
+                    from,
+                    sender: peer_name.to_string(),
+                    transfer_id: *transfer_id,
+                    filename,
+                    size: *size,
+                    hash,
+                    kind: *kind,
+                    mime,
+                });
+            }
+            MessageType::FileRequest {
+                transfer_id,
+                offset,
+            } => {
+                self.start_upload(from, peer_key, peer_name, *transfer_id, *offset)
+                    .await;
+            }
+            MessageType::FileChunk {
+                transfer_id,
+                offset,
+                data,
+            } => {
+                if let Err(e) = self.receive_chunk(from, *transfer_id, *offset, data).await {
+                    eprintln!("{} {}", "File transfer error:".red(), e);
+                }
+            }
+            MessageType::FileDeclined { transfer_id } => {
+                let filename = self.finished(from, peer_key, transfer_id).await;
+                println!(
+                    "{} {} {}",
+                    peer_name.blue(),
+                    "declined".yellow(),
+                    filename.yellow()
+                );
+            }
+            MessageType::FileComplete {
+                transfer_id,
+                verified,
+            } => {
+                let filename = self.finished(from, peer_key, transfer_id).await;
+                if *verified {
+                    println!(
+                        "{} {} {}",
+                        peer_name.blue(),
+                        "received".green(),
+                        filename.yellow()
+                    );
+                } else {
+                    println!(
+                        "{} {} {}",
+                        peer_name.blue(),
+                        "failed to verify".red(),
+                        filename.yellow()
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Records that `peer_key` is done with `transfer_id`, one way or the
+    /// other, and stops serving the file once every recipient is. Returns
+    /// the file's name.
+    async fn finished(
+        &self,
+        from: SocketAddr,
+        peer_key: &PeerKey,
+        transfer_id: &TransferId,
+    ) -> String {
+        self.streams.lock().await.remove(&(from, *transfer_id));
+        let mut outgoing = self.outgoing.lock().await;
+        let Some(file) = outgoing.get_mut(transfer_id) else {
+            return "file".to_string();
+        };
+        let filename = file.filename.clone();
+        if file.pending.remove(peer_key) && file.pending.is_empty() {
+            outgoing.remove(transfer_id);
+        }
+        filename
+    }
+
+    /// Accepts offer `number`, or the only pending offer if none is given.
+    pub async fn accept(self: &Arc<Self>, number: Option<u32>) {
+        let Some(offer) = self.take_offer(number).await else {
+            return;
+        };
+        if let Err(e) = self.start_download(offer).await {
+            eprintln!("{} {}", "Cannot receive file:".red(), e);
+        }
+    }
+
+    /// Declines offer `number`; the sender is told and no data is sent.
+    pub async fn reject(&self, number: Option<u32>) {
+        let Some(offer) = self.take_offer(number).await else {
+            return;
+        };
+        println!("{} {}", "Rejected".yellow(), offer.filename.yellow());
+        let message = self.message(MessageType::FileDeclined {
+            transfer_id: offer.transfer_id,
+        });
+        self.send(offer.from, &message);
+    }
+
+    /// Removes the chosen offer, explaining why when there isn't exactly one match.
+    async fn take_offer(&self, number: Option<u32>) -> Option<PendingOffer> {
+        let mut offers = self.offers.lock().await;
+        let index = match number {
+            Some(number) => offers.iter().position(|offer| offer.number == number),
+            None if offers.len() == 1 => Some(0),
+            None => None,
+        };
+
+        if let Some(index) = index {
+            return Some(offers.remove(index));
+        }
+
+        if offers.is_empty() {
+            println!("{}", "No pending file offers.".yellow());
+        } else {
+            println!("\n{}", "Pending File Offers:".green().bold());
+            for offer in offers.iter() {
+                println!(
+                    "  {} {} from {}: {} ({}, {})",
+                    format!("#{}", offer.number).cyan(),
+                    offer.kind.label(),
+                    offer.sender.blue(),
+                    offer.filename.yellow(),
+                    offer.mime,
+                    format_size(offer.size)
+                );
+            }
+            println!();
+        }
+        None
+    }
+
+    /// Asks `from` to resend whatever is still missing from its transfers,
+    /// e.g. after the connection carrying them dropped.
+    pub async fn resume(self: &Arc<Self>, from: SocketAddr) {
+        let pending: Vec<(TransferId, u64)> = self
+            .incoming
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, file)| file.from == from && file.received < file.size)
+            .map(|(id, file)| (*id, file.received))
+            .collect();
+
+        for (transfer_id, offset) in pending {
+            self.request(from, transfer_id, offset);
+        }
+    }
+
+    async fn start_download(self: &Arc<Self>, offer: PendingOffer) -> std::io::Result<()> {
+        let PendingOffer {
+            from,
+            sender,
+            transfer_id,
+            filename,
+            size,
+            hash,
+            mime,
+            ..
+        } = offer;
+        tokio::fs::create_dir_all(&self.download_dir).await?;
+        let part_path = self
+            .download_dir
+            .join(format!(".{}.part", hex::encode(transfer_id)));
+
+        // Pick up where an interrupted transfer of the same file left off.
+        let received = match tokio::fs::metadata(&part_path).await {
+            Ok(meta) if meta.len() <= size => meta.len(),
+            _ => {
+                File::create(&part_path).await?;
+                0
+            }
+        };
+
+        let label = format!("{} <- {}", filename, sender);
+        let mut progress = Progress::new(label, size);
+        progress.update(received);
+
+        self.incoming.lock().await.insert(
+            transfer_id,
+            IncomingFile {
+                from,
+                sender,
+                filename,
+                mime,
+                size,
+                hash,
+                received,
+                part_path,
+                progress,
+            },
+        );
+
+        if received == size {
+            tokio::spawn(self.clone().finish_download(transfer_id));
+        } else {
+            self.request(from, transfer_id, received);
+        }
+        Ok(())
+    }
+
+    fn request(&self, to: SocketAddr, transfer_id: TransferId, offset: u64) {
+        let message = self.message(MessageType::FileRequest {
+            transfer_id,
+            offset,
+        });
+        self.send(to, &message);
+    }
+
+    async fn receive_chunk(
+        self: &Arc<Self>,
+        from: SocketAddr,
+        transfer_id: TransferId,
+        offset: u64,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        let mut incoming = self.incoming.lock().await;
+        // Only the peer whose offer was accepted writes to the download.
+        let Some(file) = incoming
+            .get_mut(&transfer_id)
+            .filter(|file| file.from == from)
+        else {
+            return Ok(());
+        };
+
+        // Chunks from a superseded stream overlap what we already have.
+        if offset < file.received {
+            return Ok(());
+        }
+        if offset > file.received || file.received + data.len() as u64 > file.size {
+            let (from, received) = (file.from, file.received);
+            drop(incoming);
+            self.request(from, transfer_id, received);
+            return Ok(());
+        }
+
+        let mut part = OpenOptions::new()
+            .append(true)
+            .open(&file.part_path)
+            .await?;
+        part.write_all(data).await?;
+        file.received += data.len() as u64;
+        file.progress.update(file.received);
+
+        if file.received == file.size {
+            drop(incoming);
+            tokio::spawn(self.clone().finish_download(transfer_id));
+        }
+        Ok(())
+    }
+
+    /// Checks the BLAKE3 hash and moves the completed file into place.
+    async fn finish_download(self: Arc<Self>, transfer_id: TransferId) {
+        let Some(file) = self.incoming.lock().await.remove(&transfer_id) else {
+            return;
+        };
+
+        let verified = match hash_file(&file.part_path).await {
+            Ok(hash) => hash == file.hash,
+            Err(_) => false,
+        };
+
+        if verified {
+            let saved = async {
+                let destination = reserve_path(&self.download_dir, &file.filename).await?;
+                // Replaces only the empty file just created for it.
+                if let Err(e) = tokio::fs::rename(&file.part_path, &destination).await {
+                    let _ = tokio::fs::remove_file(&destination).await;
+                    return Err(e);
+                }
+                Ok(destination)
+            };
+            match saved.await {
+                Ok(destination) => {
+                    println!(
+                        "{} {} from {} to {}",
+                        "Saved".green(),
+                        file.filename.yellow(),
+                        file.sender.blue(),
+                        destination.display()
+                    );
+                    // The declared type is only the sender's claim; say so if
+                    // the content disagrees before anyone opens it.
+                    if let Ok(actual) = sniff_file(&destination).await
+                        && actual != file.mime
+                    {
+                        println!(
+                            "  {} {} was offered as {} but looks like {}",
+                            "Warning:".red().bold(),
+                            file.filename.yellow(),
+                            file.mime,
+                            actual
+                        );
+                    }
+                }
+                Err(e) => eprintln!("{} {}", "Failed to save file:".red(), e),
+            }
+        } else {
+            let _ = tokio::fs::remove_file(&file.part_path).await;
+            println!(
+                "{} {} from {} {}",
+                "Discarded".red(),
+                file.filename.yellow(),
+                file.sender.blue(),
+                "(hash mismatch)".red()
+            );
+        }
+
+        let message = self.message(MessageType::FileComplete {
+            transfer_id,
+            verified,
+        });
+        self.send(file.from, &message);
+    }
+
+    async fn start_upload(
+        self: &Arc<Self>,
+        to: SocketAddr,
+        peer_key: &PeerKey,
+        peer_name: &str,
+        transfer_id: TransferId,
+        offset: u64,
+    ) {
+        let Some((path, filename, size)) = self
+            .outgoing
+            .lock()
+            .await
+            .get(&transfer_id)
+            .filter(|file| file.allows(peer_key))
+            .map(|file| (file.path.clone(), file.filename.clone(), file.size))
+        else {
+            return;
+        };
+
+        let transfers = self.clone();
+        let label = format!("{} -> {}", filename, peer_name);
+        let task = tokio::spawn(async move {
+            if let Err(e) = transfers
+                .stream(to, transfer_id, &path, offset, size, label)
+                .await
+            {
+                eprintln!("{} {}", "File upload error:".red(), e);
+            }
+        });
+
+        if let Some(stale) = self.streams.lock().await.insert((to, transfer_id), task) {
+            stale.abort();
+        }
+    }
+
+    async fn stream(
+        &self,
+        to: SocketAddr,
+        transfer_id: TransferId,
+        path: &Path,
+        mut offset: u64,
+        size: u64,
+        label: String,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut file = File::open(path).await?;
+        file.seek(std::io::SeekFrom::Start(offset)).await?;
+
+        let mut progress = Progress::new(label, size);
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+
+        while offset < size {
+            let want = CHUNK_SIZE.min((size - offset) as usize);
+            file.read_exact(&mut buffer[..want]).await?;
+
+            let message = self.message(MessageType::FileChunk {
+                transfer_id,
+                offset,
+                data: buffer[..want].to_vec(),
+            });
+            self.connections
+                .send(to, bincode::serialize(&message)?.into())
+                .await?;
+
+            offset += want as u64;
+            progress.update(offset);
+        }
+        Ok(())
+    }
+
+    /// Sends `message` in the background, as dialling a peer that went
+    /// away can take a while.
+    fn send(&self, to: SocketAddr, message: &Message) {
+        let data = match bincode::serialize(message) {
+            Ok(data) => data,
+            Err(e) => {
+                eprintln!("{} {}", "File transfer error:".red(), e);
+                return;
+            }
+        };
+        let connections = self.connections.clone();
+        tokio::spawn(async move {
+            if let Err(e) = connections.send(to, data.into()).await {
+                eprintln!("{} {}", "File transfer error:".red(), e);
+            }
+        });
+    }
+
+    fn message(&self, msg_type: MessageType) -> Message {
+        Message::new(self.name.clone(), msg_type)
+    }
+}
+
+/// Single-line progress bar, redrawn in place as each percent completes.
+struct Progress {
+    label: String,
+    total: u64,
+    shown: Option<u64>,
+}
+
+impl Progress {
+    const WIDTH: u64 = 30;
+
+    fn new(label: String, total: u64) -> Self {
+        Self {
+            label,
+            total,
+            shown: None,
+        }
+    }
+
+    fn update(&mut self, done: u64) {
+        let percent = (done * 100).checked_div(self.total).unwrap_or(100);
+        if self.shown == Some(percent) {
+            return;
+        }
+        self.shown = Some(percent);
+
+        let filled = percent * Self::WIDTH / 100;
+        print!(
+            "\r{} [{}{}] {:>3}% of {}",
+            self.label,
+            "#".repeat(filled as usize).green(),
+            "-".repeat((Self::WIDTH - filled) as usize).dimmed(),
+            percent,
+            format_size(self.total)
+        );
+        if percent == 100 {
+            println!();
+        }
+        let _ = std::io::stdout().flush();
+    }
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Detects a MIME type from magic bytes, falling back to `text/plain` for
+/// readable UTF-8 such as logs and to `application/octet-stream` otherwise.
+pub fn sniff_mime(header: &[u8]) -> String {
+    if let Some(kind) = infer::get(header) {
+        return kind.mime_type().to_string();
+    }
+
+    // A multi-byte character may be cut off at the end of the sample.
+    let text = match std::str::from_utf8(header) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    };
+    if !header.is_empty() && text && !header.contains(&0) {
+        "text/plain".to_string()
+    } else {
+        UNKNOWN_MIME.to_string()
+    }
+}
+
+async fn sniff_file(path: &Path) -> std::io::Result<String> {
+    let mut file = File::open(path).await?;
+    let mut header = Vec::with_capacity(SNIFF_SIZE);
+    (&mut file)
+        .take(SNIFF_SIZE as u64)
+        .read_to_end(&mut header)
+        .await?;
+    Ok(sniff_mime(&header))
+}
+
+/// Hashes the file at `path` on a blocking thread, as a large one takes a
+/// while.
+async fn hash_file(path: &Path) -> std::io::Result<[u8; 32]> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update_reader(std::fs::File::open(path)?)?;
+        Ok(*hasher.finalize().as_bytes())
+    })
+    .await?
+}
+
+/// Turns an untrusted filename from a peer into a single, harmless path
+/// component: no directories, no hidden or device names, no control chars.
+pub fn sanitize_filename(filename: &str) -> String {
+    const MAX_LEN: usize = 200;
+    const RESERVED: [&str; 22] = [
+        "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+        "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+    ];
+
+    let base = filename.rsplit(['/', '\\']).next().unwrap_or("");
+    let mut name: String = base
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    name = name
+        .trim_start_matches(['.', ' '])
+        .trim_end_matches(['.', ' '])
+        .to_string();
+
+    if name.len() > MAX_LEN {
+        let extension = Path::new(&name)
+            .extension()
+            .and_then(|e| e.to_str())
+            .filter(|e| e.len() < 16)
+            .map(|e| format!(".{}", e))
+            .unwrap_or_default();
+        let mut cut = MAX_LEN - extension.len();
+        while !name.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        name = format!("{}{}", &name[..cut], extension);
+    }
+
+    let stem = name.split('.').next().unwrap_or("").to_ascii_uppercase();
+    if RESERVED.contains(&stem.as_str()) {
+        name.insert(0, '_');
+    }
+
+    if name.is_empty() {
+        "download".to_string()
+    } else {
+        name
+    }
+}
+
+/// Drops control characters from a MIME type a peer declared, which is
+/// only ever shown.
+fn sanitize_mime(mime: &str) -> String {
+    mime.chars().filter(|c| !c.is_control()).collect()
+}
+
+/// Claims `dir/filename` by creating it empty, adding a numeric suffix
+/// rather than touching a file that is already there, even one that
+/// appeared a moment ago.
+async fn reserve_path(dir: &Path, filename: &str) -> std::io::Result<PathBuf> {
+    let filename = sanitize_filename(filename);
+    let path = Path::new(&filename);
+    let stem = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or(&filename);
+    let extension = path.extension().and_then(|e| e.to_str());
+
+    let mut n = 0;
+    loop {
+        let candidate = match (n, extension) {
+            (0, _) => dir.join(&filename),
+            (n, Some(ext)) => dir.join(format!("{} ({}).{}", stem, n, ext)),
+            (n, None) => dir.join(format!("{} ({})", stem, n)),
+        };
+        let created = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&candidate)
+            .await;
+        match created {
+            Ok(_) => return Ok(candidate),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => n += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn directories_are_stripped() {
+        assert_eq!(sanitize_filename("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_filename("..\\..\\Windows\\evil.dll"), "evil.dll");
+        assert_eq!(sanitize_filename("/etc/shadow"), "shadow");
+        assert_eq!(sanitize_filename("C:\\Users\\x\\report.pdf"), "report.pdf");
+        assert_eq!(sanitize_filename("dir/..\\mixed/name.txt"), "name.txt");
+    }
+
+    #[test]
+    fn nothing_left_becomes_download() {
+        for name in ["", ".", "..", "...", "../", "..\\", "/", "dir/", "  . "] {
+            assert_eq!(sanitize_filename(name), "download", "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn hidden_and_trailing_dots_are_trimmed() {
+        assert_eq!(sanitize_filename(".bashrc"), "bashrc");
+        assert_eq!(sanitize_filename("report.pdf. . "), "report.pdf");
+    }
+
+    #[test]
+    fn reserved_device_names_are_prefixed() {
+        assert_eq!(sanitize_filename("CON"), "_CON");
+        assert_eq!(sanitize_filename("con.txt"), "_con.txt");
+        assert_eq!(sanitize_filename("Lpt1.tar.gz"), "_Lpt1.tar.gz");
+        assert_eq!(sanitize_filename("aux"), "_aux");
+        assert_eq!(sanitize_filename("CONSOLE.txt"), "CONSOLE.txt");
+        assert_eq!(sanitize_filename("COM10"), "COM10");
+    }
+
+    #[test]
+    fn control_and_reserved_characters_are_replaced() {
+        assert_eq!(sanitize_filename("a\nb\0c\u{7f}.txt"), "a_b_c_.txt");
+        assert_eq!(sanitize_filename("a<b>c:d\"e|f?g*h"), "a_b_c_d_e_f_g_h");
+        assert_eq!(sanitize_filename("\u{1b}[31mred"), "_[31mred");
+    }
+
+    #[test]
+    fn control_characters_are_dropped_from_mime_types() {
+        assert_eq!(sanitize_mime("image/png"), "image/png");
+        assert_eq!(
+            sanitize_mime("text/plain\r\n\u{1b}[2Kfake prompt"),
+            "text/plain[2Kfake prompt"
+        );
+    }
+
+    #[test]
+    fn long_names_are_cut_on_a_character_boundary() {
+        let name = sanitize_filename(&format!("{}.pdf", "€".repeat(100)));
+        assert!(name.len() <= 200);
+        assert!(name.ends_with(".pdf"));
+        assert!(name.trim_end_matches(".pdf").chars().all(|c| c == '€'));
+
+        let name = sanitize_filename(&"日".repeat(100));
+        assert!(name.len() <= 200);
+        assert!(name.chars().all(|c| c == '日'));
+
+        // An extension too long to be one is cut like the rest.
+        let name = sanitize_filename(&format!("a.{}", "b".repeat(300)));
+        assert_eq!(name.len(), 200);
+    }
+
+    #[tokio::test]
+    async fn reserved_paths_never_overwrite() {
+        let dir = std::env::temp_dir().join(format!(
+            "rust-chat-transfer-test-{}",
+            hex::encode(rand::random::<[u8; 8]>())
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let reserve = |name| reserve_path(&dir, name);
+
+        assert_eq!(
+            reserve("../report.pdf").await.unwrap(),
+            dir.join("report.pdf")
+        );
+        assert_eq!(
+            reserve("report.pdf").await.unwrap(),
+            dir.join("report (1).pdf")
+        );
+        assert_eq!(
+            reserve("report.pdf").await.unwrap(),
+            dir.join("report (2).pdf")
+        );
+
+        std::fs::write(dir.join("notes"), b"keep").unwrap();
+        assert_eq!(reserve("notes").await.unwrap(), dir.join("notes (1)"));
+        assert_eq!(std::fs::read(dir.join("notes")).unwrap(), b"keep");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}